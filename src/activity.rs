@@ -0,0 +1,88 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+use tracing::warn;
+
+use crate::config::{ActivityType, DisplayType};
+use crate::limits::{self, truncate};
+use crate::mpd_conn::{Timestamps, now};
+
+/// How long an activity for a song of unknown length
+/// can be replayed after it was rendered.
+const MAX_AGE: Duration = Duration::from_secs(10 * 60);
+
+/// An activity with all of its format strings rendered
+/// and its album art resolved.
+///
+/// The last activity is kept in memory,
+/// so it can be sent again on reconnect without querying MPD or MusicBrainz.
+#[derive(Debug, Clone)]
+pub struct Activity {
+    pub details: String,
+    pub state: String,
+    pub large_image: String,
+    pub small_image: String,
+    pub large_text: String,
+    pub small_text: String,
+    pub activity_type: ActivityType,
+    pub display_type: DisplayType,
+    pub buttons: Vec<(String, String)>,
+    pub party_size: Option<(u32, u32)>,
+    pub join_secret: Option<String>,
+    pub timestamps: Timestamps,
+    pub rendered_at: Instant,
+}
+
+impl Activity {
+    /// Checks whether the song this activity was rendered for
+    /// should have finished by now.
+    pub fn is_stale(&self) -> bool {
+        match self.timestamps.song_end {
+            Some(song_end) => song_end <= now(),
+            None => self.rendered_at.elapsed() > MAX_AGE,
+        }
+    }
+
+    /// Brings every field within the limits Discord accepts.
+    ///
+    /// Text is truncated with the given ellipsis.
+    /// Images and buttons which cannot be truncated without breaking them
+    /// are removed instead.
+    pub fn fit_limits(&mut self, ellipsis: &str) {
+        let text = |str: &mut String| {
+            *str = truncate(std::mem::take(str), limits::TEXT_MAX_BYTES, ellipsis);
+        };
+
+        text(&mut self.details);
+        text(&mut self.state);
+        text(&mut self.large_text);
+        text(&mut self.small_text);
+
+        // discord requires details to be set, and both lines to be at least two characters
+        self.details = limits::pad(std::mem::take(&mut self.details), limits::TEXT_MIN_CHARS);
+        if !self.state.is_empty() {
+            self.state = limits::pad(std::mem::take(&mut self.state), limits::TEXT_MIN_CHARS);
+        }
+
+        for image in [&mut self.large_image, &mut self.small_image] {
+            if image.len() > limits::IMAGE_MAX_BYTES {
+                warn!("Image '{image}' is too long for Discord, hiding it");
+                image.clear();
+            }
+        }
+
+        self.buttons.retain_mut(|(text, link)| {
+            if !limits::is_valid_url(link) {
+                warn!("Button link '{link}' is not a valid http(s) URL, hiding the button");
+                return false;
+            }
+
+            *text = truncate(
+                std::mem::take(text),
+                limits::BUTTON_LABEL_MAX_BYTES,
+                ellipsis,
+            );
+            true
+        });
+    }
+}
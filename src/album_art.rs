@@ -1,11 +1,17 @@
+use crate::cache::{AlbumEntry, ArtistImageEntry, Cache, CacheKey, Lookup, Provider, RecordType};
+use crate::config::AlbumArtConfig;
 use crate::mpd_conn::try_get_first_tag;
 use mpd_client::responses::Song;
 use mpd_client::tag::Tag;
-use reqwest::Client;
 use reqwest::header::{HeaderMap, HeaderValue};
+use reqwest::{Certificate, Client, Proxy, Url};
 use serde::Deserialize;
 use std::collections::HashMap;
-use std::fmt::{Display, Formatter};
+use std::fs;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+use tracing::{debug, error};
 
 static APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
 
@@ -33,33 +39,122 @@ struct ReleaseCoverArt {
     front: bool,
 }
 
-#[derive(Debug, Copy, Clone)]
-enum Type {
-    Release,
-    ReleaseGroup,
+/// Response body of both ISRC lookups and recording searches.
+#[derive(Deserialize, Debug)]
+struct RecordingList {
+    recordings: Vec<Recording>,
 }
 
-impl Display for Type {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}",
-            match self {
-                Self::Release => "release",
-                Self::ReleaseGroup => "release-group",
-            }
-        )
-    }
+#[derive(Deserialize, Debug)]
+struct Recording {
+    #[serde(default)]
+    releases: Vec<RecordingRelease>,
+}
+
+#[derive(Deserialize, Debug)]
+struct RecordingRelease {
+    id: String,
+    status: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ArtistSearchResult {
+    artists: Vec<Artist>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Artist {
+    id: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct ArtistRelations {
+    #[serde(default)]
+    relations: Vec<Relation>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Relation {
+    #[serde(rename = "type")]
+    relation_type: String,
+    url: Option<RelationUrl>,
+}
+
+#[derive(Deserialize, Debug)]
+struct RelationUrl {
+    resource: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct WikidataEntities {
+    entities: HashMap<String, WikidataEntity>,
+}
+
+#[derive(Deserialize, Debug)]
+struct WikidataEntity {
+    claims: WikidataClaims,
+}
+
+#[derive(Deserialize, Debug)]
+struct WikidataClaims {
+    /// The "image" property
+    #[serde(rename = "P18", default)]
+    image: Vec<WikidataClaim>,
+}
+
+#[derive(Deserialize, Debug)]
+struct WikidataClaim {
+    mainsnak: WikidataSnak,
+}
+
+#[derive(Deserialize, Debug)]
+struct WikidataSnak {
+    datavalue: Option<WikidataValue>,
+}
+
+#[derive(Deserialize, Debug)]
+struct WikidataValue {
+    value: String,
+}
+
+/// Maximum number of releases to check for artwork
+/// when resolving art from a recording.
+const MAX_RELEASE_LOOKUPS: usize = 3;
+
+/// MusicBrainz allows an average of one request per second.
+/// https://musicbrainz.org/doc/MusicBrainz_API/Rate_Limiting
+const MUSICBRAINZ_INTERVAL: Duration = Duration::from_secs(1);
+
+const WIKIDATA_URL: &str = "https://www.wikidata.org";
+const COMMONS_URL: &str = "https://commons.wikimedia.org";
+
+/// How long to wait before looking for an artist's image again
+/// after failing to find one.
+const MISSING_ARTIST_IMAGE_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// The result of pre-resolving an album into the cache.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WarmOutcome {
+    Cached,
+    Resolved,
+    Unmatched,
 }
 
 pub struct AlbumArtClient {
-    release_group_cache: HashMap<(String, String), (String, Type)>,
+    cache: Cache,
     client: Client,
+    musicbrainz_url: String,
+    coverartarchive_url: String,
+    next_request: Mutex<Instant>,
+    /// Artists without an image, and when they were looked up
+    missing_artist_images: HashMap<String, Instant>,
+    /// Whether to only use art which is already cached
+    cache_only: bool,
 }
 
 impl AlbumArtClient {
-    pub fn new() -> Self {
-        let release_group_cache = HashMap::new();
+    pub fn new(config: &AlbumArtConfig) -> Self {
+        let cache = Cache::load();
 
         let mut header_map = HeaderMap::new();
         header_map.insert(
@@ -67,24 +162,82 @@ impl AlbumArtClient {
             HeaderValue::from_str("application/json").expect("Failed to parse content type"),
         );
 
-        let client = Client::builder()
+        let mut builder = Client::builder()
             .user_agent(APP_USER_AGENT)
             .default_headers(header_map)
-            .build()
-            .expect("Failed to create HTTP client");
+            .connect_timeout(Duration::from_secs(config.connect_timeout))
+            .read_timeout(Duration::from_secs(config.read_timeout));
+
+        if !config.proxy.is_empty() {
+            match Proxy::all(&config.proxy) {
+                Ok(proxy) => builder = builder.proxy(proxy),
+                Err(err) => error!("Invalid album art proxy '{}': {err}", config.proxy),
+            }
+        }
+
+        builder = builder.tls_certs_merge(load_certificates(&config.ca_certificates));
+
+        let client = builder.build().expect("Failed to create HTTP client");
 
         Self {
-            release_group_cache,
+            cache,
             client,
+            musicbrainz_url: config.musicbrainz_url.trim_end_matches('/').to_string(),
+            coverartarchive_url: config.coverartarchive_url.trim_end_matches('/').to_string(),
+            next_request: Mutex::new(Instant::now()),
+            missing_artist_images: HashMap::new(),
+            cache_only: false,
+        }
+    }
+
+    /// Only uses art which is already cached,
+    /// without looking anything up or writing to the cache.
+    pub fn cache_only(mut self) -> Self {
+        self.cache_only = true;
+        self
+    }
+
+    /// Writes the lookup cache to disk.
+    pub fn save_cache(&mut self) {
+        if self.cache_only {
+            return;
+        }
+
+        if let Err(err) = self.cache.save() {
+            error!("{err}");
+        }
+    }
+
+    /// Waits until the next request to MusicBrainz
+    /// can be sent without exceeding its rate limit.
+    async fn throttle(&self) {
+        let wait = {
+            let mut next_request = self
+                .next_request
+                .lock()
+                .expect("Failed to lock rate limiter");
+            let now = Instant::now();
+            let wait = next_request.saturating_duration_since(now);
+            *next_request = now.max(*next_request) + MUSICBRAINZ_INTERVAL;
+            wait
+        };
+
+        if !wait.is_zero() {
+            debug!("Waiting {wait:?} for MusicBrainz rate limit");
+            sleep(wait).await;
         }
     }
 
     /// Looks up a release by its UUID on MusicBrainz.
     /// If the release has a cover, returns the ID of that record.
     /// If not, returns the ID of its release group.
-    async fn get_record_id(&self, release_id: &str) -> Option<(String, Type)> {
-        let url = format!("https://musicbrainz.org/ws/2/release/{release_id}?inc=release-groups");
+    async fn get_record_id(&self, release_id: &str) -> Option<(String, RecordType)> {
+        let url = format!(
+            "{}/ws/2/release/{release_id}?inc=release-groups",
+            self.musicbrainz_url
+        );
 
+        self.throttle().await;
         let response = self.client.get(&url).send().await;
 
         match response {
@@ -92,9 +245,9 @@ impl AlbumArtClient {
                 let response = response.json::<Release>().await;
                 response.ok().map(|release| {
                     if release.cover_art_archive.front {
-                        (release.id, Type::Release)
+                        (release.id, RecordType::Release)
                     } else {
-                        (release.release_group.id, Type::ReleaseGroup)
+                        (release.release_group.id, RecordType::ReleaseGroup)
                     }
                 })
             }
@@ -105,72 +258,408 @@ impl AlbumArtClient {
     /// Searches for a release on MusicBrainz
     /// Returns its ID if one is found.
     async fn find_release_group_id(&self, artist: &str, album: &str) -> Option<String> {
-        let query = format!("artist:{artist} AND release:{album}");
-        let url = format!("https://musicbrainz.org/ws/2/release-group/?query={query}&limit=1",);
+        let query = format!(
+            "artist:\"{}\" AND release:\"{}\"",
+            escape_query(artist),
+            escape_query(album)
+        );
+        let url = format!("{}/ws/2/release-group/", self.musicbrainz_url);
 
-        let response = self.client.get(&url).send().await;
+        self.throttle().await;
+        let response = self
+            .client
+            .get(&url)
+            .query(&[("query", query.as_str()), ("limit", "1")])
+            .send()
+            .await;
 
-        if let Ok(response) = response {
-            if response.status() != 200 {
-                return None;
+        match response {
+            Ok(response) if response.status() == 200 => {
+                let mut response = response.json::<SearchResult>().await.ok()?;
+                response.release_groups.pop().map(|rg| rg.id)
             }
+            _ => None,
+        }
+    }
+
+    /// Looks up the releases of a recording by its ISRC on MusicBrainz.
+    async fn find_isrc_releases(&self, isrc: &str) -> Vec<RecordingRelease> {
+        let url = format!("{}/ws/2/isrc/{isrc}?inc=releases", self.musicbrainz_url);
+
+        self.throttle().await;
+        let response = self.client.get(&url).send().await;
+        Self::get_recording_releases(response).await
+    }
+
+    /// Searches for a recording on MusicBrainz
+    /// and returns the releases it appears on.
+    async fn find_recording_releases(&self, artist: &str, title: &str) -> Vec<RecordingRelease> {
+        let query = format!(
+            "artist:\"{}\" AND recording:\"{}\"",
+            escape_query(artist),
+            escape_query(title)
+        );
+        let url = format!("{}/ws/2/recording/", self.musicbrainz_url);
+
+        self.throttle().await;
+        let response = self
+            .client
+            .get(&url)
+            .query(&[("query", query.as_str()), ("limit", "5")])
+            .send()
+            .await;
+        Self::get_recording_releases(response).await
+    }
 
-            let mut response = response
-                .json::<SearchResult>()
+    async fn get_recording_releases(
+        response: reqwest::Result<reqwest::Response>,
+    ) -> Vec<RecordingRelease> {
+        match response {
+            Ok(response) if response.status() == 200 => response
+                .json::<RecordingList>()
                 .await
-                .expect("Received response from MusicBrainz in unexpected format");
+                .map(|list| {
+                    list.recordings
+                        .into_iter()
+                        .flat_map(|recording| recording.releases)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            _ => vec![],
+        }
+    }
 
-            response.release_groups.pop().map(|rg| rg.id)
-        } else {
-            None
+    /// Checks the first few official releases for a front cover,
+    /// returning the first release which has one.
+    /// If none do, falls back to the release group of the first release.
+    async fn get_first_record_id(
+        &self,
+        releases: Vec<RecordingRelease>,
+    ) -> Option<(String, RecordType)> {
+        let mut fallback = None;
+
+        let official = releases
+            .iter()
+            .filter(|release| release.status.as_deref() == Some("Official"))
+            .take(MAX_RELEASE_LOOKUPS);
+
+        for release in official {
+            match self.get_record_id(&release.id).await {
+                Some((id, RecordType::Release)) => return Some((id, RecordType::Release)),
+                Some(id) if fallback.is_none() => fallback = Some(id),
+                _ => {}
+            }
         }
+
+        fallback
     }
 
-    fn get_cache_key(song: &Song) -> Option<(String, String)> {
+    fn get_cache_key(song: &Song) -> Option<CacheKey> {
         let tags = &song.tags;
-        let artist = try_get_first_tag(tags.get(&Tag::AlbumArtist))
-            .or(try_get_first_tag(tags.get(&Tag::Artist)));
         let album = try_get_first_tag(tags.get(&Tag::Album));
 
-        match (artist, album) {
-            (Some(artist), Some(album)) => Some((artist.to_string(), album.to_string())),
-            _ => None,
+        if let Some(album) = album {
+            let artist = try_get_first_tag(tags.get(&Tag::AlbumArtist))
+                .or(try_get_first_tag(tags.get(&Tag::Artist)))?;
+
+            Some(CacheKey::Album {
+                artist: artist.to_string(),
+                album: album.to_string(),
+            })
+        } else {
+            let artist = try_get_first_tag(tags.get(&Tag::Artist))
+                .or(try_get_first_tag(tags.get(&Tag::AlbumArtist)))?;
+            let title = song.title()?;
+
+            Some(CacheKey::Recording {
+                artist: artist.to_string(),
+                title: title.to_string(),
+            })
         }
     }
 
-    /// Attempts to get the URL to the current album's front cover
-    /// by fetching it from MusicBrainz.
+    /// Resolves the record to fetch the cover for.
     ///
-    /// Uses MPD's internal MusicBrainz album ID tag if its set,
-    /// otherwise falls back to searching.
-    pub async fn get_album_art_url(&mut self, song: Song) -> Option<String> {
-        let cache_key = Self::get_cache_key(&song);
-
-        if let Some(cache_key) = cache_key {
-            let id = if let Some(id) = self.release_group_cache.remove(&cache_key) {
-                Some(id)
-            } else {
-                let release_id = try_get_first_tag(song.tags.get(&Tag::MusicBrainzReleaseId));
+    /// Albums use MPD's internal MusicBrainz album ID tag if it's set,
+    /// otherwise fall back to searching.
+    /// Tracks without an album use their ISRC tag if set,
+    /// otherwise fall back to a recording search.
+    async fn resolve(
+        &self,
+        cache_key: &CacheKey,
+        release_id: Option<&str>,
+        isrc: Option<&str>,
+    ) -> Option<AlbumEntry> {
+        let (id, record_type, lookup) = match cache_key {
+            CacheKey::Album { artist, album } => {
                 if let Some(release_id) = release_id {
-                    self.get_record_id(release_id).await
+                    let (id, record_type) = self.get_record_id(release_id).await?;
+                    (id, record_type, Lookup::Mbid)
                 } else {
-                    self.find_release_group_id(&cache_key.0, &cache_key.1)
-                        .await
-                        .map(|id| (id, Type::ReleaseGroup))
+                    let id = self.find_release_group_id(artist, album).await?;
+                    (id, RecordType::ReleaseGroup, Lookup::Search)
                 }
-            };
-
-            if let Some((id, record_type)) = id {
-                self.release_group_cache
-                    .insert(cache_key, (id.clone(), record_type));
-                Some(format!(
-                    "https://coverartarchive.org/{record_type}/{id}/front-250"
-                ))
-            } else {
-                None
             }
+            CacheKey::Recording { artist, title } => {
+                let (releases, lookup) = if let Some(isrc) = isrc {
+                    (self.find_isrc_releases(isrc).await, Lookup::Isrc)
+                } else {
+                    (
+                        self.find_recording_releases(artist, title).await,
+                        Lookup::RecordingSearch,
+                    )
+                };
+
+                let (id, record_type) = self.get_first_record_id(releases).await?;
+                (id, record_type, lookup)
+            }
+        };
+
+        Some(AlbumEntry {
+            id,
+            record_type,
+            provider: Provider::CoverArtArchive,
+            lookup,
+        })
+    }
+
+    /// Attempts to get the URL to the current album's front cover
+    /// by fetching it from MusicBrainz.
+    pub async fn get_album_art_url(&mut self, song: Song) -> Option<String> {
+        let cache_key = Self::get_cache_key(&song)?;
+
+        self.cache.reload_if_changed();
+
+        let entry = if let Some(entry) = self.cache.albums.get(&cache_key) {
+            entry.clone()
+        } else if self.cache_only {
+            return None;
         } else {
-            None
+            let release_id = try_get_first_tag(song.tags.get(&Tag::MusicBrainzReleaseId));
+            let isrc = try_get_first_tag(song.tags.get(&Tag::Other("ISRC".into())));
+
+            let entry = self.resolve(&cache_key, release_id, isrc).await?;
+            self.cache.albums.insert(cache_key, entry.clone());
+            self.save_cache();
+            entry
+        };
+
+        Some(format!(
+            "{}/{}/{}/front-250",
+            self.coverartarchive_url, entry.record_type, entry.id
+        ))
+    }
+
+    /// Resolves an album into the cache without fetching its cover,
+    /// skipping albums which are already cached.
+    ///
+    /// The release is looked up by its MusicBrainz ID if it has one,
+    /// the same as when the album is played.
+    pub async fn warm(
+        &mut self,
+        artist: &str,
+        album: &str,
+        release_id: Option<&str>,
+    ) -> WarmOutcome {
+        let cache_key = CacheKey::Album {
+            artist: artist.to_string(),
+            album: album.to_string(),
+        };
+
+        if self.cache.albums.contains_key(&cache_key) {
+            return WarmOutcome::Cached;
+        }
+
+        match self.resolve(&cache_key, release_id, None).await {
+            Some(entry) => {
+                self.cache.albums.insert(cache_key, entry);
+                self.save_cache();
+                WarmOutcome::Resolved
+            }
+            None => WarmOutcome::Unmatched,
+        }
+    }
+
+    /// Searches for an artist on MusicBrainz
+    /// Returns its ID if one is found.
+    async fn find_artist_id(&self, artist: &str) -> Option<String> {
+        let query = format!("artist:\"{}\"", escape_query(artist));
+        let url = format!("{}/ws/2/artist/", self.musicbrainz_url);
+
+        self.throttle().await;
+        let response = self
+            .client
+            .get(&url)
+            .query(&[("query", query.as_str()), ("limit", "1")])
+            .send()
+            .await;
+
+        match response {
+            Ok(response) if response.status() == 200 => {
+                let response = response.json::<ArtistSearchResult>().await;
+                response.ok()?.artists.pop().map(|artist| artist.id)
+            }
+            _ => None,
+        }
+    }
+
+    /// Looks up an artist's URL relationships on MusicBrainz
+    /// and finds their image on Wikimedia Commons.
+    ///
+    /// Uses the artist's image relationship if they have one,
+    /// otherwise falls back to the image on their Wikidata entry.
+    async fn get_artist_image(&self, artist_id: &str) -> Option<(String, Provider)> {
+        let url = format!(
+            "{}/ws/2/artist/{artist_id}?inc=url-rels",
+            self.musicbrainz_url
+        );
+
+        self.throttle().await;
+        let response = self.client.get(&url).send().await;
+
+        let relations = match response {
+            Ok(response) if response.status() == 200 => {
+                response.json::<ArtistRelations>().await.ok()?.relations
+            }
+            _ => return None,
+        };
+
+        let find_relation = |relation_type: &str| {
+            relations
+                .iter()
+                .filter(|relation| relation.relation_type == relation_type)
+                .find_map(|relation| relation.url.as_ref())
+                .map(|url| url.resource.as_str())
+        };
+
+        if let Some(file_name) = find_relation("image")
+            .and_then(|resource| resource.rsplit_once("/File:"))
+            .map(|(_, file_name)| file_name)
+        {
+            // already percent-encoded as part of the relationship URL
+            return Some((
+                format!("{COMMONS_URL}/wiki/Special:FilePath/{file_name}?width=250"),
+                Provider::WikimediaCommons,
+            ));
+        }
+
+        let entity_id = find_relation("wikidata")?.rsplit('/').next()?;
+        let file_name = self.get_wikidata_image(entity_id).await?;
+
+        let mut url = Url::parse(COMMONS_URL).ok()?;
+        url.path_segments_mut()
+            .ok()?
+            .extend(["wiki", "Special:FilePath", &file_name]);
+        url.query_pairs_mut().append_pair("width", "250");
+
+        Some((url.to_string(), Provider::Wikidata))
+    }
+
+    /// Gets the file name of the image attached to a Wikidata entity.
+    async fn get_wikidata_image(&self, entity_id: &str) -> Option<String> {
+        let url = format!("{WIKIDATA_URL}/wiki/Special:EntityData/{entity_id}.json");
+
+        let response = self.client.get(&url).send().await;
+
+        match response {
+            Ok(response) if response.status() == 200 => {
+                let mut response = response.json::<WikidataEntities>().await.ok()?;
+                response
+                    .entities
+                    .remove(entity_id)?
+                    .claims
+                    .image
+                    .into_iter()
+                    .find_map(|claim| claim.mainsnak.datavalue)
+                    .map(|value| value.value)
+            }
+            _ => None,
+        }
+    }
+
+    /// Finds the image for a song's artist.
+    ///
+    /// Uses MPD's internal MusicBrainz artist ID tag if it's set,
+    /// otherwise falls back to searching.
+    async fn find_artist_image(&self, artist: &str, song: &Song) -> Option<ArtistImageEntry> {
+        let (artist_id, lookup) = match try_get_first_tag(song.tags.get(&Tag::MusicBrainzArtistId))
+        {
+            Some(artist_id) => (artist_id.to_string(), Lookup::Mbid),
+            None => (self.find_artist_id(artist).await?, Lookup::Search),
+        };
+
+        let (url, provider) = self.get_artist_image(&artist_id).await?;
+
+        Some(ArtistImageEntry {
+            artist_id,
+            url,
+            provider,
+            lookup,
+        })
+    }
+
+    /// Attempts to get the URL to a photo of the current song's artist
+    /// by following its MusicBrainz artist to Wikimedia Commons.
+    ///
+    /// Artists without an image are remembered for a while,
+    /// so they are not looked up again for every track.
+    pub async fn get_artist_image_url(&mut self, song: &Song) -> Option<String> {
+        let tags = &song.tags;
+        let artist = try_get_first_tag(tags.get(&Tag::Artist))
+            .or(try_get_first_tag(tags.get(&Tag::AlbumArtist)))?;
+
+        self.cache.reload_if_changed();
+
+        if let Some(entry) = self.cache.artist_images.get(artist) {
+            return Some(entry.url.clone());
+        }
+
+        if self.cache_only
+            || self
+                .missing_artist_images
+                .get(artist)
+                .is_some_and(|looked_up| looked_up.elapsed() < MISSING_ARTIST_IMAGE_TTL)
+        {
+            return None;
         }
+
+        let Some(entry) = self.find_artist_image(artist, song).await else {
+            debug!("No image found for artist '{artist}'");
+            self.missing_artist_images
+                .insert(artist.to_string(), Instant::now());
+            return None;
+        };
+
+        let url = entry.url.clone();
+        self.missing_artist_images.remove(artist);
+        self.cache.artist_images.insert(artist.to_string(), entry);
+        self.save_cache();
+
+        Some(url)
     }
 }
+
+/// Escapes characters which have special meaning
+/// inside a quoted Lucene search term.
+fn escape_query(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Reads each of the given PEM files,
+/// returning every certificate found in them.
+/// Files which cannot be read or parsed are logged and skipped.
+fn load_certificates(paths: &[String]) -> Vec<Certificate> {
+    paths
+        .iter()
+        .flat_map(|path| {
+            let certs = fs::read(path)
+                .map_err(|err| err.to_string())
+                .and_then(|pem| Certificate::from_pem_bundle(&pem).map_err(|err| err.to_string()));
+
+            certs.unwrap_or_else(|err| {
+                error!("Failed to load CA certificate '{path}': {err}");
+                vec![]
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,294 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tracing::{debug, error};
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub enum RecordType {
+    Release,
+    ReleaseGroup,
+}
+
+impl Display for RecordType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Release => "release",
+                Self::ReleaseGroup => "release-group",
+            }
+        )
+    }
+}
+
+/// The service an image was found on.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum Provider {
+    CoverArtArchive,
+    WikimediaCommons,
+    Wikidata,
+}
+
+impl Display for Provider {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::CoverArtArchive => "Cover Art Archive",
+                Self::WikimediaCommons => "Wikimedia Commons",
+                Self::Wikidata => "Wikidata",
+            }
+        )
+    }
+}
+
+/// How the MusicBrainz entity behind an entry was found.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum Lookup {
+    /// Read from a MusicBrainz ID tag
+    Mbid,
+    /// Searched for by name
+    Search,
+    /// Looked up by the ISRC tag
+    Isrc,
+    /// Searched for by artist and track title
+    RecordingSearch,
+}
+
+impl Display for Lookup {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Mbid => "MusicBrainz ID tag",
+                Self::Search => "search",
+                Self::Isrc => "ISRC tag",
+                Self::RecordingSearch => "recording search",
+            }
+        )
+    }
+}
+
+/// Identifies a cached album art lookup.
+/// Tracks without an album tag are looked up by their recording instead.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum CacheKey {
+    Album { artist: String, album: String },
+    Recording { artist: String, title: String },
+}
+
+impl Display for CacheKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Album { artist, album } => write!(f, "{artist} - {album}"),
+            Self::Recording { artist, title } => write!(f, "{artist} - {title} (recording)"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AlbumEntry {
+    pub id: String,
+    pub record_type: RecordType,
+    pub provider: Provider,
+    pub lookup: Lookup,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ArtistImageEntry {
+    pub artist_id: String,
+    pub url: String,
+    pub provider: Provider,
+    pub lookup: Lookup,
+}
+
+/// On-disk representation of the cache.
+/// This is also the format used for exports and imports.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct CacheFile {
+    #[serde(default)]
+    pub albums: Vec<CachedAlbum>,
+    #[serde(default)]
+    pub artist_images: Vec<CachedArtistImage>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CachedAlbum {
+    #[serde(flatten)]
+    pub key: CacheKey,
+    #[serde(flatten)]
+    pub entry: AlbumEntry,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CachedArtistImage {
+    pub artist: String,
+    #[serde(flatten)]
+    pub entry: ArtistImageEntry,
+}
+
+/// Persistent cache of album art and artist image lookups.
+///
+/// The cache file is re-read whenever it is modified by another process,
+/// so entries edited through the CLI are picked up by a running daemon.
+#[derive(Debug, Default)]
+pub struct Cache {
+    path: Option<PathBuf>,
+    modified: Option<SystemTime>,
+    pub albums: HashMap<CacheKey, AlbumEntry>,
+    pub artist_images: HashMap<String, ArtistImageEntry>,
+}
+
+impl Cache {
+    /// Loads the cache from the user's cache directory.
+    pub fn load() -> Self {
+        let path = dirs::cache_dir().map(|dir| dir.join("mpd-discord-rpc").join("album_art.json"));
+
+        let mut cache = Self {
+            path,
+            ..Self::default()
+        };
+        cache.reload();
+        cache
+    }
+
+    /// Re-reads the cache file if it has changed on disk since it was last read or written.
+    pub fn reload_if_changed(&mut self) {
+        if self.path.is_some() && self.read_modified() != self.modified {
+            debug!("Album art cache changed on disk, reloading");
+            self.reload();
+        }
+    }
+
+    fn reload(&mut self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+
+        let file = load_cache(path);
+        self.albums.clear();
+        self.artist_images.clear();
+        self.merge(file);
+        self.modified = self.read_modified();
+    }
+
+    /// Writes the cache to disk, so it survives restarts.
+    pub fn save(&mut self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        save_cache(path, &self.to_file()).map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("Failed to save album art cache '{}': {err}", path.display()),
+            )
+        })?;
+
+        self.modified = self.read_modified();
+        Ok(())
+    }
+
+    /// Adds all entries from the given file,
+    /// replacing any existing entries with the same key.
+    pub fn merge(&mut self, file: CacheFile) {
+        self.albums.extend(
+            file.albums
+                .into_iter()
+                .map(|album| (album.key, album.entry)),
+        );
+        self.artist_images.extend(
+            file.artist_images
+                .into_iter()
+                .map(|image| (image.artist, image.entry)),
+        );
+    }
+
+    pub fn to_file(&self) -> CacheFile {
+        let mut albums = self
+            .albums
+            .iter()
+            .map(|(key, entry)| CachedAlbum {
+                key: key.clone(),
+                entry: entry.clone(),
+            })
+            .collect::<Vec<_>>();
+        albums.sort_by_key(|album| album.key.to_string());
+
+        let mut artist_images = self
+            .artist_images
+            .iter()
+            .map(|(artist, entry)| CachedArtistImage {
+                artist: artist.clone(),
+                entry: entry.clone(),
+            })
+            .collect::<Vec<_>>();
+        artist_images.sort_by(|a, b| a.artist.cmp(&b.artist));
+
+        CacheFile {
+            albums,
+            artist_images,
+        }
+    }
+
+    fn read_modified(&self) -> Option<SystemTime> {
+        self.path
+            .as_deref()
+            .and_then(|path| fs::metadata(path).ok())
+            .and_then(|metadata| metadata.modified().ok())
+    }
+}
+
+/// Reads a cache file from disk.
+/// A missing or unreadable file is treated as empty.
+fn load_cache(path: &Path) -> CacheFile {
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == ErrorKind::NotFound => return CacheFile::default(),
+        Err(err) => {
+            error!("Failed to read album art cache '{}': {err}", path.display());
+            return CacheFile::default();
+        }
+    };
+
+    serde_json::from_slice(&contents).unwrap_or_else(|err| {
+        error!(
+            "Failed to parse album art cache '{}': {err}",
+            path.display()
+        );
+        CacheFile::default()
+    })
+}
+
+/// Writes the cache to a temporary file
+/// and moves it into place, so an interrupted write
+/// never leaves a truncated cache behind.
+fn save_cache(path: &Path, cache: &CacheFile) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let contents = serde_json::to_vec_pretty(cache)?;
+
+    // unique to each write, as several processes may share the cache
+    let tmp_path = path.with_extension(format!(
+        "json.{}-{:x}.tmp",
+        std::process::id(),
+        RandomState::new().build_hasher().finish()
+    ));
+    fs::write(&tmp_path, contents)?;
+    fs::rename(tmp_path, path)
+}
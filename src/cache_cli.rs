@@ -0,0 +1,165 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::cache::{AlbumEntry, ArtistImageEntry, Cache, CacheFile, CacheKey};
+use crate::cli::CacheCommand;
+
+/// Runs a `cache` subcommand.
+/// Returns an error message if the command failed.
+pub fn run(command: CacheCommand) -> Result<(), String> {
+    let mut cache = Cache::load();
+
+    match command {
+        CacheCommand::List => {
+            print_matches(&cache, "", false);
+            Ok(())
+        }
+        CacheCommand::Search { query } => {
+            print_matches(&cache, &query, false);
+            Ok(())
+        }
+        CacheCommand::Show { query } => {
+            print_matches(&cache, &query, true);
+            Ok(())
+        }
+        CacheCommand::Delete { query } => delete(&mut cache, &query),
+        CacheCommand::Export { file } => export(&cache, file.as_deref()),
+        CacheCommand::Import { file } => import(&mut cache, &file),
+    }
+}
+
+/// Checks whether a cache key matches a case-insensitive search query.
+/// An empty query matches everything.
+fn album_matches(key: &CacheKey, query: &str) -> bool {
+    let (artist, name) = match key {
+        CacheKey::Album { artist, album } => (artist, album),
+        CacheKey::Recording { artist, title } => (artist, title),
+    };
+
+    contains(artist, query) || contains(name, query)
+}
+
+fn contains(value: &str, query: &str) -> bool {
+    value.to_lowercase().contains(&query.to_lowercase())
+}
+
+fn print_matches(cache: &Cache, query: &str, detailed: bool) {
+    let file = cache.to_file();
+    let mut found = false;
+
+    for album in file
+        .albums
+        .iter()
+        .filter(|album| album_matches(&album.key, query))
+    {
+        found = true;
+        if detailed {
+            print_album_details(&album.key, &album.entry);
+        } else {
+            println!(
+                "album   {}: {}/{}",
+                album.key, album.entry.record_type, album.entry.id
+            );
+        }
+    }
+
+    for image in file
+        .artist_images
+        .iter()
+        .filter(|image| contains(&image.artist, query))
+    {
+        found = true;
+        if detailed {
+            print_artist_image_details(&image.artist, &image.entry);
+        } else {
+            println!("artist  {}: {}", image.artist, image.entry.url);
+        }
+    }
+
+    if !found {
+        println!("No matching entries");
+    }
+}
+
+fn print_album_details(key: &CacheKey, entry: &AlbumEntry) {
+    println!("{key}");
+    println!("  record:   {} {}", entry.record_type, entry.id);
+    println!("  provider: {}", entry.provider);
+    println!("  lookup:   {}", entry.lookup);
+    println!();
+}
+
+fn print_artist_image_details(artist: &str, entry: &ArtistImageEntry) {
+    println!("{artist} (artist image)");
+    println!("  artist:   {}", entry.artist_id);
+    println!("  url:      {}", entry.url);
+    println!("  provider: {}", entry.provider);
+    println!("  lookup:   {}", entry.lookup);
+    println!();
+}
+
+fn delete(cache: &mut Cache, query: &str) -> Result<(), String> {
+    let albums = cache
+        .albums
+        .keys()
+        .filter(|key| album_matches(key, query))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let artists = cache
+        .artist_images
+        .keys()
+        .filter(|artist| contains(artist, query))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    if albums.is_empty() && artists.is_empty() {
+        println!("No matching entries");
+        return Ok(());
+    }
+
+    for key in &albums {
+        cache.albums.remove(key);
+    }
+
+    for artist in &artists {
+        cache.artist_images.remove(artist);
+    }
+
+    cache.save().map_err(|err| err.to_string())?;
+
+    for key in &albums {
+        println!("Deleted album {key}");
+    }
+
+    for artist in &artists {
+        println!("Deleted artist image {artist}");
+    }
+
+    Ok(())
+}
+
+fn export(cache: &Cache, path: Option<&Path>) -> Result<(), String> {
+    let contents = serde_json::to_string_pretty(&cache.to_file()).map_err(|err| err.to_string())?;
+
+    match path {
+        Some(path) => fs::write(path, contents)
+            .map_err(|err| format!("Failed to write '{}': {err}", path.display())),
+        None => writeln!(io::stdout(), "{contents}").map_err(|err| err.to_string()),
+    }
+}
+
+fn import(cache: &mut Cache, path: &Path) -> Result<(), String> {
+    let contents =
+        fs::read(path).map_err(|err| format!("Failed to read '{}': {err}", path.display()))?;
+    let file = serde_json::from_slice::<CacheFile>(&contents)
+        .map_err(|err| format!("Failed to parse '{}': {err}", path.display()))?;
+
+    let count = file.albums.len() + file.artist_images.len();
+    cache.merge(file);
+    cache.save().map_err(|err| err.to_string())?;
+
+    println!("Imported {count} entries");
+    Ok(())
+}
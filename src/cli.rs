@@ -0,0 +1,83 @@
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+use tracing::level_filters::LevelFilter;
+
+use crate::config::{Config, LoadOptions};
+
+/// Displays your currently playing song / album / artist from MPD in Discord using Rich Presence.
+#[derive(Parser, Debug)]
+#[command(version)]
+pub struct Cli {
+    /// Path of the config file [default: ~/.config/discord-rpc/config.toml]
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// MPD host to connect to instead of the configured ones.
+    /// Can be given more than once
+    #[arg(long = "host", value_name = "ADDRESS")]
+    pub hosts: Vec<String>,
+
+    /// Discord application ID to use instead of the configured one
+    #[arg(long, value_name = "ID")]
+    pub app_id: Option<u64>,
+
+    /// Most verbose level to log at: off, error, warn, info, debug or trace
+    #[arg(long, value_name = "LEVEL", default_value = "info")]
+    pub log_level: LevelFilter,
+
+    /// Prints the default config and exits
+    #[arg(long)]
+    pub print_default_config: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Checks the config file for errors and exits
+    CheckConfig,
+    /// Prints the status for the song playing on MPD, without connecting to Discord
+    Render,
+    /// Prints the version and exits
+    Version,
+    /// Resolves the album art for every album in the library into the cache
+    WarmCache,
+    /// Manages the album art cache
+    #[command(subcommand)]
+    Cache(CacheCommand),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CacheCommand {
+    /// Lists every cached entry
+    List,
+    /// Lists entries whose artist, album or title contain the query
+    Search { query: String },
+    /// Shows full details of matching entries
+    Show { query: String },
+    /// Deletes matching entries, so they are looked up again
+    Delete { query: String },
+    /// Writes the cache as JSON to a file, or stdout
+    Export {
+        #[arg(value_name = "PATH")]
+        file: Option<PathBuf>,
+    },
+    /// Merges entries from a JSON export into the cache
+    Import {
+        #[arg(value_name = "PATH")]
+        file: PathBuf,
+    },
+}
+
+impl Cli {
+    /// Gets where to load the config from and what to override in it.
+    pub fn load_options(&self) -> LoadOptions {
+        LoadOptions {
+            path: self.config.clone().unwrap_or_else(Config::default_path),
+            hosts: self.hosts.clone(),
+            id: self.app_id,
+        }
+    }
+}
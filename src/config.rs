@@ -1,6 +1,16 @@
-use serde::{Deserialize, Serialize};
+use regex::Regex;
+use serde::de::value::MapAccessDeserializer;
+use serde::de::{self, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
 use std::default::Default;
-use universal_config::ConfigLoader;
+use std::fmt::{self, Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::{env, fs, io};
+use tracing::{debug, error, info, warn};
+
+/// Prefix of the environment variables which override config keys
+const ENV_PREFIX: &str = "MPD_DISCORD_RPC_";
 
 #[derive(Serialize, Deserialize, Copy, Clone, Debug, Default)]
 #[serde(rename_all = "snake_case")]
@@ -21,7 +31,69 @@ pub enum DisplayType {
     Details,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PartySizeMode {
+    #[default]
+    Off,
+    Queue,
+    Album,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ActivityType {
+    #[default]
+    Listening,
+    Playing,
+    Watching,
+    Competing,
+}
+
+/// How to choose between MPD hosts when more than one is playing.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HostSelection {
+    /// The first playing host in the list
+    #[default]
+    Priority,
+    /// The host which most recently started playing
+    MostRecent,
+    /// The current host, until it stops playing
+    Sticky,
+}
+
+/// Overrides the display type for each activity type.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct DisplayTypes {
+    pub listening: Option<DisplayType>,
+    pub playing: Option<DisplayType>,
+    pub watching: Option<DisplayType>,
+    pub competing: Option<DisplayType>,
+}
+
+impl DisplayTypes {
+    pub const fn get(&self, activity_type: ActivityType) -> Option<DisplayType> {
+        match activity_type {
+            ActivityType::Listening => self.listening,
+            ActivityType::Playing => self.playing,
+            ActivityType::Watching => self.watching,
+            ActivityType::Competing => self.competing,
+        }
+    }
+}
+
+/// Overrides the activity and display type
+/// for songs where a formatting token matches a pattern.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Rule {
+    pub token: String,
+    pub matches: String,
+    pub activity_type: Option<ActivityType>,
+    pub display_type: Option<DisplayType>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Format {
     #[serde(default = "default_details_format")]
     pub details: String,
@@ -31,6 +103,8 @@ pub struct Format {
     pub timestamp: TimestampMode,
     #[serde(default = "default_image")]
     pub large_image: String,
+    #[serde(default = "default_true")]
+    pub album_art: bool,
     #[serde(default = "default_image")]
     pub small_image: String,
     #[serde(default)]
@@ -40,6 +114,12 @@ pub struct Format {
     #[serde(default)]
     pub display_type: DisplayType,
     #[serde(default)]
+    pub activity_type: ActivityType,
+    #[serde(default)]
+    pub party_size: PartySizeMode,
+    #[serde(default)]
+    pub allow_join: bool,
+    #[serde(default)]
     pub button1_text: String,
     #[serde(default)]
     pub button1_link: String,
@@ -47,6 +127,16 @@ pub struct Format {
     pub button2_text: String,
     #[serde(default)]
     pub button2_link: String,
+    #[serde(default)]
+    pub listenbrainz_user: String,
+    #[serde(default = "default_ellipsis")]
+    pub ellipsis: String,
+    #[serde(default)]
+    pub truncate_order: Vec<String>,
+    #[serde(default)]
+    pub display_types: DisplayTypes,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
 }
 
 impl Default for Format {
@@ -56,14 +146,170 @@ impl Default for Format {
             state: default_state_format(),
             timestamp: TimestampMode::default(),
             large_image: default_image(),
+            album_art: true,
             small_image: default_image(),
             large_text: String::new(),
             small_text: String::new(),
             display_type: DisplayType::default(),
+            activity_type: ActivityType::default(),
+            party_size: PartySizeMode::default(),
+            allow_join: false,
             button1_text: String::new(),
             button1_link: String::new(),
             button2_text: String::new(),
             button2_link: String::new(),
+            listenbrainz_user: String::new(),
+            ellipsis: default_ellipsis(),
+            truncate_order: vec![],
+            display_types: DisplayTypes::default(),
+            rules: vec![],
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct AlbumArtConfig {
+    #[serde(default = "default_musicbrainz_url")]
+    pub musicbrainz_url: String,
+    #[serde(default = "default_coverartarchive_url")]
+    pub coverartarchive_url: String,
+    #[serde(default)]
+    pub proxy: String,
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout: u64,
+    #[serde(default = "default_read_timeout")]
+    pub read_timeout: u64,
+    #[serde(default)]
+    pub ca_certificates: Vec<String>,
+}
+
+impl Default for AlbumArtConfig {
+    fn default() -> Self {
+        Self {
+            musicbrainz_url: default_musicbrainz_url(),
+            coverartarchive_url: default_coverartarchive_url(),
+            proxy: String::new(),
+            connect_timeout: default_connect_timeout(),
+            read_timeout: default_read_timeout(),
+            ca_certificates: vec![],
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MpdOfflineConfig {
+    #[serde(default = "default_check_interval")]
+    pub check_interval: u64,
+    #[serde(default)]
+    pub details: String,
+    #[serde(default)]
+    pub state: String,
+    #[serde(default = "default_image")]
+    pub large_image: String,
+}
+
+impl Default for MpdOfflineConfig {
+    fn default() -> Self {
+        Self {
+            check_interval: default_check_interval(),
+            details: String::new(),
+            state: String::new(),
+            large_image: default_image(),
+        }
+    }
+}
+
+/// An MPD host, which can be given as just its address or as a table.
+#[derive(Serialize, Clone, Debug)]
+pub struct Host {
+    pub address: String,
+    /// Display name, which is the address if not set
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    /// File to read the password from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password_file: Option<PathBuf>,
+    /// Discord application ID to use while this host is active
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    /// Format to use while this host is active
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<Format>,
+}
+
+#[derive(Deserialize)]
+struct HostTable {
+    address: String,
+    #[serde(default)]
+    name: String,
+    password: Option<String>,
+    password_file: Option<PathBuf>,
+    id: Option<u64>,
+    format: Option<Format>,
+}
+
+impl From<HostTable> for Host {
+    fn from(table: HostTable) -> Self {
+        Self {
+            name: if table.name.is_empty() {
+                display_name(&table.address)
+            } else {
+                table.name
+            },
+            address: table.address,
+            password: table.password,
+            password_file: table.password_file,
+            id: table.id,
+            format: table.format,
+        }
+    }
+}
+
+/// Accepts either form of host directly, rather than trying each in turn,
+/// so errors inside a host table point at the key which caused them.
+impl<'de> Deserialize<'de> for Host {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct HostVisitor;
+
+        impl<'de> Visitor<'de> for HostVisitor {
+            type Value = Host;
+
+            fn expecting(&self, f: &mut Formatter) -> fmt::Result {
+                f.write_str("an address or a table with an address")
+            }
+
+            fn visit_str<E: de::Error>(self, address: &str) -> Result<Host, E> {
+                Ok(Host::from(address.to_string()))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Host, A::Error> {
+                HostTable::deserialize(MapAccessDeserializer::new(map)).map(Host::from)
+            }
+        }
+
+        deserializer.deserialize_any(HostVisitor)
+    }
+}
+
+/// Gets the name to show for a host address,
+/// leaving out any password given in it.
+fn display_name(address: &str) -> String {
+    match address.split_once('@') {
+        Some((password, host)) if !password.is_empty() => host.to_string(),
+        _ => address.to_string(),
+    }
+}
+
+impl From<String> for Host {
+    fn from(address: String) -> Self {
+        Self {
+            name: display_name(&address),
+            address,
+            password: None,
+            password_file: None,
+            id: None,
+            format: None,
         }
     }
 }
@@ -73,9 +319,17 @@ pub struct Config {
     #[serde(default = "default_discord_id")]
     pub id: u64,
     #[serde(default = "default_mpd_hosts")]
-    pub hosts: Vec<String>,
+    pub hosts: Vec<Host>,
+    #[serde(default)]
+    pub host_selection: HostSelection,
+    #[serde(default)]
+    pub discord_socket: String,
     #[serde(default)]
     pub format: Format,
+    #[serde(default)]
+    pub album_art: AlbumArtConfig,
+    #[serde(default)]
+    pub mpd_offline: MpdOfflineConfig,
 }
 
 impl Default for Config {
@@ -83,24 +337,326 @@ impl Default for Config {
         Self {
             id: default_discord_id(),
             hosts: default_mpd_hosts(),
+            host_selection: HostSelection::default(),
+            discord_socket: String::new(),
             format: Format::default(),
+            album_art: AlbumArtConfig::default(),
+            mpd_offline: MpdOfflineConfig::default(),
         }
     }
 }
 
+/// Why the config file could not be loaded.
+#[derive(Debug)]
+pub enum ConfigError {
+    Io {
+        path: PathBuf,
+        err: io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        line: usize,
+        column: usize,
+        key: Option<String>,
+        message: String,
+    },
+    Invalid {
+        path: PathBuf,
+        key: String,
+        message: String,
+    },
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io { path, err } => {
+                write!(f, "Failed to read config file '{}': {err}", path.display())
+            }
+            Self::Parse {
+                path,
+                line,
+                column,
+                key,
+                message,
+            } => {
+                write!(
+                    f,
+                    "Invalid config file '{}' at line {line}, column {column}",
+                    path.display()
+                )?;
+                if let Some(key) = key {
+                    write!(f, " (key `{key}`)")?;
+                }
+                write!(f, ": {message}")
+            }
+            Self::Invalid { path, key, message } => {
+                write!(
+                    f,
+                    "Invalid config file '{}': key `{key}` {message}",
+                    path.display()
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Where to load the config from,
+/// along with values given on the command line to apply over it.
+#[derive(Debug, Clone)]
+pub struct LoadOptions {
+    pub path: PathBuf,
+    /// Replaces the configured hosts if not empty
+    pub hosts: Vec<String>,
+    pub id: Option<u64>,
+}
+
 impl Config {
-    pub fn load() -> Self {
-        let loader =
-            ConfigLoader::new("discord-rpc").with_formats(&[universal_config::Format::Toml]);
-
-        loader.find_and_load().unwrap_or_else(|_| {
-            let cfg = Self::default();
-            loader
-                .save(&cfg, &universal_config::Format::Toml)
-                .expect("Failed to create default config file");
-            cfg
+    /// Gets the default path of the config file,
+    /// which is `~/.config/discord-rpc/config.toml` on Linux.
+    pub fn default_path() -> PathBuf {
+        dirs::config_dir()
+            .unwrap_or_default()
+            .join("discord-rpc")
+            .join("config.toml")
+    }
+
+    /// Loads the config file, creating it with the defaults if it does not exist.
+    ///
+    /// An existing file is never overwritten,
+    /// so a file which fails to parse is left as it is and returned as an error.
+    pub fn load(options: &LoadOptions) -> Result<Self, ConfigError> {
+        let path = &options.path;
+
+        let config = match fs::read_to_string(path) {
+            Ok(source) => Self::parse(path, &source)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                Self::save_default(path);
+                Self::default()
+            }
+            Err(err) => {
+                return Err(ConfigError::Io {
+                    path: path.clone(),
+                    err,
+                });
+            }
+        };
+
+        config.finish(options)
+    }
+
+    /// Reads an existing config file, such as when reloading it while running.
+    ///
+    /// Unlike [`Config::load`], a missing file is an error rather than recreated,
+    /// as editors can briefly remove the file while saving it.
+    pub fn read(options: &LoadOptions) -> Result<Self, ConfigError> {
+        let path = &options.path;
+
+        let source = fs::read_to_string(path).map_err(|err| ConfigError::Io {
+            path: path.clone(),
+            err,
+        })?;
+
+        Self::parse(path, &source)?.finish(options)
+    }
+
+    /// Applies environment and command line overrides, then validates the result.
+    fn finish(self, options: &LoadOptions) -> Result<Self, ConfigError> {
+        let mut config = self.with_env_overrides();
+
+        if !options.hosts.is_empty() {
+            config.hosts = options.hosts.iter().cloned().map(Host::from).collect();
+        }
+
+        if let Some(id) = options.id {
+            config.id = id;
+        }
+
+        config.validate(&options.path)?;
+
+        Ok(config)
+    }
+
+    fn parse(path: &Path, source: &str) -> Result<Self, ConfigError> {
+        toml::from_str(source).map_err(|err| {
+            let offset = err.span().map_or(0, |span| span.start);
+            let before = &source[..offset];
+
+            let line = before.matches('\n').count() + 1;
+            let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+
+            ConfigError::Parse {
+                path: path.to_path_buf(),
+                line,
+                column,
+                key: key_at(source, offset),
+                message: err.message().to_string(),
+            }
         })
     }
+
+    /// Serializes the default config, as written to a new config file.
+    ///
+    /// `hosts` is left out, so that it keeps following `MPD_HOST` and `MPD_PORT`
+    /// rather than saving them to disk along with any password they contain.
+    pub fn default_toml() -> Result<String, toml::ser::Error> {
+        let mut table = toml::Table::try_from(Self::default())?;
+        table.remove("hosts");
+        toml::to_string_pretty(&table)
+    }
+
+    /// Writes a newly created config to disk.
+    /// Failing to do so is not fatal, as the config is still usable.
+    fn save_default(path: &Path) {
+        let res = Self::default_toml()
+            .map_err(|err| err.to_string())
+            .and_then(|toml| {
+                if let Some(dir) = path.parent() {
+                    fs::create_dir_all(dir).map_err(|err| err.to_string())?;
+                }
+                fs::write(path, toml).map_err(|err| err.to_string())
+            });
+
+        match res {
+            Ok(()) => info!("Created default config file at '{}'", path.display()),
+            Err(err) => warn!(
+                "Failed to create default config file at '{}': {err}",
+                path.display()
+            ),
+        }
+    }
+
+    /// Checks for values which parse but cannot be used.
+    fn validate(&self, path: &Path) -> Result<(), ConfigError> {
+        let invalid = |key: String, message: &str| ConfigError::Invalid {
+            path: path.to_path_buf(),
+            key,
+            message: message.to_string(),
+        };
+
+        if self.hosts.is_empty() {
+            return Err(invalid(
+                "hosts".to_string(),
+                "must contain at least one host",
+            ));
+        }
+
+        for (i, host) in self.hosts.iter().enumerate() {
+            if host.address.is_empty() {
+                return Err(invalid(format!("hosts[{i}].address"), "must not be empty"));
+            }
+        }
+
+        let formats = std::iter::once(("format".to_string(), &self.format)).chain(
+            self.hosts.iter().enumerate().filter_map(|(i, host)| {
+                Some((format!("hosts[{i}].format"), host.format.as_ref()?))
+            }),
+        );
+
+        for (key, format) in formats {
+            for (i, rule) in format.rules.iter().enumerate() {
+                if let Err(err) = Regex::new(&rule.matches) {
+                    return Err(invalid(
+                        format!("{key}.rules[{i}].matches"),
+                        &format!("is not a valid regular expression: {err}"),
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Applies `MPD_DISCORD_RPC_*` environment variables over the config.
+    ///
+    /// Nested keys are separated by a double underscore,
+    /// so `MPD_DISCORD_RPC_FORMAT__LARGE_IMAGE` sets `format.large_image`.
+    /// Values for string keys are used as is,
+    /// and all others are parsed as JSON, falling back to a string.
+    fn with_env_overrides(self) -> Self {
+        // anything which is not valid unicode cannot be a config override
+        let overrides = env::vars_os()
+            .filter_map(|(key, value)| {
+                let key = key.to_str()?.strip_prefix(ENV_PREFIX)?.to_string();
+                Some((key, value.into_string().ok()?))
+            })
+            .collect();
+
+        self.with_overrides(overrides)
+    }
+
+    /// Overrides config keys, given as paths without [`ENV_PREFIX`].
+    fn with_overrides(self, overrides: Vec<(String, String)>) -> Self {
+        if overrides.is_empty() {
+            return self;
+        }
+
+        let mut value = match serde_json::to_value(&self) {
+            Ok(value) => value,
+            Err(err) => {
+                error!("Failed to apply config overrides from the environment: {err}");
+                return self;
+            }
+        };
+
+        for (key, raw) in overrides {
+            let path = key.to_lowercase();
+
+            let Some(target) = path
+                .split("__")
+                .try_fold(&mut value, |value, key| value.get_mut(key))
+            else {
+                warn!("Ignoring {ENV_PREFIX}{key}, as it does not match a config key");
+                continue;
+            };
+
+            *target = if target.is_string() {
+                Value::String(raw)
+            } else {
+                serde_json::from_str(&raw).unwrap_or(Value::String(raw))
+            };
+
+            debug!(
+                "Overriding config key '{}' from {ENV_PREFIX}{key}",
+                path.replace("__", ".")
+            );
+        }
+
+        match serde_json::from_value(value) {
+            Ok(config) => config,
+            Err(err) => {
+                error!("Invalid config override in the environment, ignoring overrides: {err}");
+                self
+            }
+        }
+    }
+
+    /// Gets the format for a host, falling back to the global one.
+    pub fn format_for(&self, host: Option<usize>) -> &Format {
+        host.and_then(|host| self.hosts.get(host)?.format.as_ref())
+            .unwrap_or(&self.format)
+    }
+
+    /// Gets the Discord application ID for a host, falling back to the global one.
+    pub fn id_for(&self, host: Option<usize>) -> u64 {
+        host.and_then(|host| self.hosts.get(host)?.id)
+            .unwrap_or(self.id)
+    }
+
+    /// Checks whether switching to another config
+    /// requires reconnecting to the MPD hosts.
+    pub fn hosts_changed(&self, other: &Self) -> bool {
+        self.host_selection != other.host_selection
+            || self.hosts.len() != other.hosts.len()
+            || self.hosts.iter().zip(&other.hosts).any(|(a, b)| {
+                a.address != b.address
+                    || a.name != b.name
+                    || a.password != b.password
+                    || a.password_file != b.password_file
+            })
+    }
 }
 
 fn default_details_format() -> String {
@@ -111,14 +667,227 @@ fn default_state_format() -> String {
     "$artist / $album".to_string()
 }
 
-fn default_image() -> String {
+pub fn default_image() -> String {
     "notes".to_string()
 }
 
+const fn default_true() -> bool {
+    true
+}
+
 const fn default_discord_id() -> u64 {
     677226551607033903
 }
 
-fn default_mpd_hosts() -> Vec<String> {
-    vec!["localhost:6600".to_string()]
+fn default_ellipsis() -> String {
+    "…".to_string()
+}
+
+/// Finds the key being defined at an offset in a TOML file,
+/// including the table it is in.
+fn key_at(source: &str, offset: usize) -> Option<String> {
+    let line_start = source[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let line = source[line_start..]
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .trim();
+
+    let table = |line: &str| {
+        line.strip_prefix('[').map(|header| {
+            header
+                .trim_matches(|c| c == '[' || c == ']')
+                .trim()
+                .to_string()
+        })
+    };
+
+    if let Some(table) = table(line) {
+        return Some(table);
+    }
+
+    let key = line.split_once('=')?.0.trim();
+
+    let table = source[..line_start]
+        .lines()
+        .rev()
+        .find_map(|line| table(line.trim()));
+
+    Some(match table {
+        Some(table) => format!("{table}.{key}"),
+        None => key.to_string(),
+    })
+}
+
+/// Gets the default host from the `MPD_HOST` and `MPD_PORT` variables,
+/// the same way other MPD clients do.
+fn default_mpd_hosts() -> Vec<Host> {
+    let host = env::var("MPD_HOST").ok();
+    let port = env::var("MPD_PORT").ok();
+
+    vec![Host::from(env_address(host.as_deref(), port.as_deref()))]
+}
+
+/// Builds a host address from the values of `MPD_HOST` and `MPD_PORT`.
+fn env_address(host: Option<&str>, port: Option<&str>) -> String {
+    let host = host.unwrap_or("localhost");
+    let port = port.unwrap_or("6600");
+
+    let (password, address) = match host.split_once('@') {
+        Some((password, address)) if !password.is_empty() => (Some(password), address),
+        _ => (None, host),
+    };
+
+    let address = if address.starts_with('/') || address.starts_with('@') {
+        // sockets have no port
+        address.to_string()
+    } else if address.contains(':') && !address.starts_with('[') {
+        format!("[{address}]:{port}")
+    } else {
+        format!("{address}:{port}")
+    };
+
+    match password {
+        Some(password) => format!("{password}@{address}"),
+        None => address,
+    }
+}
+
+fn default_musicbrainz_url() -> String {
+    "https://musicbrainz.org".to_string()
+}
+
+fn default_coverartarchive_url() -> String {
+    "https://coverartarchive.org".to_string()
+}
+
+const fn default_connect_timeout() -> u64 {
+    10
+}
+
+const fn default_read_timeout() -> u64 {
+    30
+}
+
+const fn default_check_interval() -> u64 {
+    10
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overrides(overrides: &[(&str, &str)]) -> Config {
+        let overrides = overrides
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+
+        Config::default().with_overrides(overrides)
+    }
+
+    #[test]
+    fn key_at_finds_keys_and_tables() {
+        let source = "id = 1\n\n[format]\ndetails = 5\n\n[[hosts]]\naddress = 5\n";
+        let at = |text: &str| key_at(source, source.find(text).unwrap());
+
+        assert_eq!(at("1").as_deref(), Some("id"));
+        assert_eq!(at("5").as_deref(), Some("format.details"));
+        assert_eq!(at("hosts").as_deref(), Some("hosts"));
+        assert_eq!(at("address").as_deref(), Some("hosts.address"));
+    }
+
+    #[test]
+    fn key_at_without_key() {
+        assert_eq!(key_at("# comment\n", 2), None);
+        assert_eq!(key_at("", 0), None);
+    }
+
+    #[test]
+    fn host_table_errors_point_at_key() {
+        let source = "[[hosts]]\naddress = \"localhost\"\nformat = { details = 5 }\n";
+        let Err(ConfigError::Parse { line, key, .. }) =
+            Config::parse(Path::new("config.toml"), source)
+        else {
+            panic!("config should fail to parse");
+        };
+
+        assert_eq!(line, 3);
+        assert_eq!(key.as_deref(), Some("hosts.format"));
+    }
+
+    #[test]
+    fn env_address_defaults() {
+        assert_eq!(env_address(None, None), "localhost:6600");
+        assert_eq!(env_address(Some("music.local"), None), "music.local:6600");
+        assert_eq!(env_address(None, Some("6601")), "localhost:6601");
+    }
+
+    #[test]
+    fn env_address_passwords() {
+        assert_eq!(
+            env_address(Some("secret@music.local"), Some("6601")),
+            "secret@music.local:6601"
+        );
+        assert_eq!(
+            env_address(Some("secret@/run/mpd/socket"), None),
+            "secret@/run/mpd/socket"
+        );
+    }
+
+    #[test]
+    fn env_address_sockets() {
+        assert_eq!(
+            env_address(Some("/run/mpd/socket"), Some("6601")),
+            "/run/mpd/socket"
+        );
+        assert_eq!(env_address(Some("@mpd"), None), "@mpd");
+        assert_eq!(env_address(Some("secret@@mpd"), None), "secret@@mpd");
+    }
+
+    #[test]
+    fn env_address_ipv6() {
+        assert_eq!(env_address(Some("::1"), None), "[::1]:6600");
+        assert_eq!(env_address(Some("[::1]"), Some("6601")), "[::1]:6601");
+        assert_eq!(
+            env_address(Some("secret@fe80::1"), None),
+            "secret@[fe80::1]:6600"
+        );
+    }
+
+    #[test]
+    fn overrides_set_nested_keys() {
+        let config = overrides(&[
+            ("FORMAT__LARGE_IMAGE", "cover"),
+            ("FORMAT__ALBUM_ART", "false"),
+            ("ALBUM_ART__READ_TIMEOUT", "5"),
+        ]);
+
+        assert_eq!(config.format.large_image, "cover");
+        assert!(!config.format.album_art);
+        assert_eq!(config.album_art.read_timeout, 5);
+    }
+
+    #[test]
+    fn overrides_keep_strings_as_is() {
+        let config = overrides(&[("FORMAT__DETAILS", "123"), ("DISCORD_SOCKET", "0")]);
+
+        assert_eq!(config.format.details, "123");
+        assert_eq!(config.discord_socket, "0");
+    }
+
+    #[test]
+    fn overrides_ignore_unknown_keys() {
+        let config = overrides(&[("FORMAT__NOT_A_KEY", "x"), ("ID", "42")]);
+
+        assert_eq!(config.id, 42);
+    }
+
+    #[test]
+    fn overrides_ignored_when_invalid() {
+        let config = overrides(&[("ID", "not a number"), ("FORMAT__DETAILS", "$album")]);
+
+        assert_eq!(config.id, default_discord_id());
+        assert_eq!(config.format.details, default_details_format());
+    }
 }
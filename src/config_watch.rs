@@ -0,0 +1,98 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+use tokio::time::{Instant, sleep_until};
+use tracing::{debug, warn};
+
+/// How long to wait for more changes after the first one,
+/// since editors often write a file in several steps.
+const DEBOUNCE: Duration = Duration::from_millis(250);
+
+/// Watches the config file for changes.
+pub struct ConfigWatcher {
+    /// Kept alive to keep watching, or `None` if watching is not possible
+    watcher: Option<RecommendedWatcher>,
+    rx: mpsc::UnboundedReceiver<()>,
+    /// When to report a change that has been seen, once the debounce has passed
+    pending: Option<Instant>,
+}
+
+impl ConfigWatcher {
+    /// Starts watching the config file.
+    ///
+    /// The directory containing it is watched rather than the file itself,
+    /// so that changes are still seen after an editor replaces the file.
+    pub fn new(path: &Path) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        let file_name = path.file_name().map(PathBuf::from);
+
+        // a bare file name has an empty parent, which cannot be watched
+        let dir = path.parent().map(|dir| {
+            if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            }
+        });
+
+        let watcher = dir.and_then(|dir| {
+            let watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+                match res {
+                    Ok(event)
+                        if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+                            && event
+                                .paths
+                                .iter()
+                                .any(|path| path.file_name().map(PathBuf::from) == file_name) =>
+                    {
+                        let _ = tx.send(());
+                    }
+                    Ok(_) => {}
+                    Err(err) => debug!("Config watcher error: {err}"),
+                }
+            })
+            .and_then(|mut watcher| {
+                watcher.watch(dir, RecursiveMode::NonRecursive)?;
+                Ok(watcher)
+            });
+
+            match &watcher {
+                Ok(_) => debug!("Watching '{}' for config changes", dir.display()),
+                Err(err) => warn!(
+                    "Failed to watch config file for changes, send SIGHUP to reload it instead: {err}"
+                ),
+            }
+
+            watcher.ok()
+        });
+
+        Self {
+            watcher,
+            rx,
+            pending: None,
+        }
+    }
+
+    /// Waits until the config file has changed.
+    ///
+    /// This is cancel safe, so can be used inside `tokio::select!`.
+    pub async fn changed(&mut self) {
+        let deadline = match self.pending {
+            Some(deadline) => deadline,
+            None => {
+                if self.watcher.is_none() || self.rx.recv().await.is_none() {
+                    return std::future::pending().await;
+                }
+
+                *self.pending.insert(Instant::now() + DEBOUNCE)
+            }
+        };
+
+        sleep_until(deadline).await;
+        self.pending = None;
+        while self.rx.try_recv().is_ok() {}
+    }
+}
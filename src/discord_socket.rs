@@ -0,0 +1,358 @@
+use std::collections::HashMap;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use tokio::process::{Child, Command};
+use tokio::time::interval;
+use tracing::{debug, error, info, warn};
+
+use crate::signals::{Signal, Signals};
+
+/// Setting which shows the status in every running Discord client.
+pub const ALL: &str = "all";
+
+/// Points each copy started by [`run_all`] at its own socket.
+const CHILD_SOCKET: &str = "DISCORD_RPC_CHILD_SOCKET";
+
+/// Set on every copy started by [`run_all`] but one,
+/// so that only a single copy looks up album art and writes the cache.
+const CHILD_CACHE_ONLY: &str = "DISCORD_RPC_CHILD_CACHE_ONLY";
+
+/// How often to look for Discord clients which have started or stopped.
+const RESCAN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Directories which Discord builds are known to create their IPC socket in,
+/// relative to the runtime directory.
+const SOCKET_DIRS: &[&str] = &[
+    "",
+    "app/com.discordapp.Discord",
+    "app/com.discordapp.DiscordCanary",
+    "app/com.discordapp.DiscordPTB",
+    "snap.discord",
+    "snap.discord-canary",
+];
+
+/// The number of sockets Discord may create in each directory,
+/// when multiple clients are running at once.
+const MAX_SOCKETS: u8 = 10;
+
+/// Gets the directory the Discord client looks for sockets in,
+/// using the same environment variables it does.
+fn runtime_dir() -> PathBuf {
+    ["XDG_RUNTIME_DIR", "TMPDIR", "TMP", "TEMP"]
+        .iter()
+        .find_map(env::var_os)
+        .map_or_else(|| PathBuf::from("/tmp"), PathBuf::from)
+}
+
+/// Finds the Discord IPC sockets which currently exist.
+///
+/// Sockets in the runtime directory (stable, PTB and Canary)
+/// are listed first, followed by Flatpak and Snap builds.
+pub fn discover() -> Vec<PathBuf> {
+    discover_in(&runtime_dir())
+}
+
+fn discover_in(runtime_dir: &Path) -> Vec<PathBuf> {
+    SOCKET_DIRS
+        .iter()
+        .flat_map(|dir| {
+            let dir = runtime_dir.join(dir);
+            (0..MAX_SOCKETS).map(move |i| dir.join(format!("discord-ipc-{i}")))
+        })
+        .filter(|path| path.exists())
+        .collect()
+}
+
+/// Gets the socket setting to use,
+/// which for copies started by [`run_all`] is the socket they were started for.
+pub fn setting(config: &str) -> String {
+    env::var(CHILD_SOCKET).unwrap_or_else(|_| config.to_string())
+}
+
+/// Whether this is a copy started by [`run_all`]
+/// which should only show album art that is already cached.
+pub fn is_cache_only() -> bool {
+    env::var_os(CHILD_CACHE_ONLY).is_some()
+}
+
+/// Points the Discord client at the socket chosen in the config.
+///
+/// The setting can be empty to let the client pick the first socket it finds,
+/// an index into the sockets returned by [`discover`],
+/// or an explicit path to a socket.
+/// [`ALL`] is handled by [`run_all`] instead.
+///
+/// The Discord client locates its socket through environment variables,
+/// so this must be called before any other threads are started.
+/// The returned redirect must be kept alive for as long as the client runs,
+/// and [`Redirect::relink`] called before each time it connects.
+pub fn select(setting: &str) -> Result<Option<Redirect>, String> {
+    if setting.is_empty() {
+        return Ok(None);
+    }
+
+    let target = match setting.parse::<usize>() {
+        Ok(index) => Target::Index(index),
+        Err(_) => Target::Path(PathBuf::from(setting)),
+    };
+
+    let mut redirect = redirect(target)?;
+    redirect.relink();
+
+    // relinking only reports a missing socket once it has gone away
+    if redirect.linked.is_none() {
+        redirect.report_missing();
+    }
+
+    Ok(Some(redirect))
+}
+
+/// The socket chosen in the config.
+enum Target {
+    Index(usize),
+    Path(PathBuf),
+}
+
+/// The directory created to point the Discord client at a single socket,
+/// which is removed again when dropped.
+pub struct Redirect {
+    dir: PathBuf,
+    /// The directory sockets are discovered in, from before it was redirected
+    runtime_dir: PathBuf,
+    target: Target,
+    /// The socket currently linked to
+    linked: Option<PathBuf>,
+}
+
+impl Redirect {
+    /// Points the link at the chosen socket again,
+    /// as an index may refer to a client which has since started or stopped.
+    ///
+    /// If there is no socket at the index, the link is removed
+    /// rather than falling back to another client.
+    pub fn relink(&mut self) {
+        let socket = match &self.target {
+            Target::Index(index) => {
+                let sockets = discover_in(&self.runtime_dir);
+                debug!("Found Discord sockets: {sockets:?}");
+                sockets.into_iter().nth(*index)
+            }
+            Target::Path(path) => Some(path.clone()),
+        };
+
+        if socket == self.linked {
+            return;
+        }
+
+        let link = self.dir.join("discord-ipc-0");
+        if let Err(err) = std::fs::remove_file(&link)
+            && err.kind() != std::io::ErrorKind::NotFound
+        {
+            warn!(
+                "Failed to remove Discord socket link '{}': {err}",
+                link.display()
+            );
+        }
+        self.linked = None;
+
+        let Some(socket) = socket else {
+            self.report_missing();
+            return;
+        };
+
+        match symlink(&socket, &link) {
+            Ok(()) => {
+                info!("Using Discord socket '{}'", socket.display());
+                self.linked = Some(socket);
+            }
+            Err(err) => error!(
+                "Failed to link Discord socket '{}': {err}",
+                socket.display()
+            ),
+        }
+    }
+
+    fn report_missing(&self) {
+        if let Target::Index(index) = self.target {
+            warn!(
+                "No Discord socket found at index {index}, waiting for that Discord client to start"
+            );
+        }
+    }
+}
+
+impl Drop for Redirect {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_dir_all(&self.dir) {
+            debug!(
+                "Failed to remove Discord socket link '{}': {err}",
+                self.dir.display()
+            );
+        }
+    }
+}
+
+/// Creates a directory to hold a link to the chosen socket,
+/// and makes it the directory the Discord client searches.
+#[cfg(unix)]
+fn redirect(target: Target) -> Result<Redirect, String> {
+    let dir = dirs::runtime_dir()
+        .unwrap_or_else(env::temp_dir)
+        .join(format!("mpd-discord-rpc-{}", std::process::id()));
+
+    std::fs::create_dir_all(&dir).map_err(|err| {
+        format!(
+            "Failed to create Discord socket link directory '{}': {err}",
+            dir.display()
+        )
+    })?;
+
+    let runtime_dir = runtime_dir();
+
+    // SAFETY: called before the async runtime or any other threads are started,
+    // so nothing can be reading the environment concurrently.
+    unsafe {
+        env::set_var("XDG_RUNTIME_DIR", &dir);
+    }
+
+    Ok(Redirect {
+        dir,
+        runtime_dir,
+        target,
+        linked: None,
+    })
+}
+
+#[cfg(not(unix))]
+fn redirect(_target: Target) -> Result<Redirect, String> {
+    Err("Choosing a Discord socket is only supported on Unix".to_string())
+}
+
+#[cfg(unix)]
+fn symlink(original: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(original, link)
+}
+
+#[cfg(not(unix))]
+fn symlink(_original: &Path, _link: &Path) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Shows the status in every running Discord client,
+/// by running a copy of this program for each socket.
+///
+/// The Discord client finds its socket through process-wide environment variables,
+/// so each socket needs a process of its own.
+/// Sockets are looked for again regularly to follow clients starting and stopping,
+/// and signals are passed on to every copy.
+///
+/// Only one copy looks up album art, so MusicBrainz's rate limit is kept to
+/// and the cache has a single writer. The others show art once it is cached.
+pub async fn run_all() {
+    let exe = match env::current_exe() {
+        Ok(exe) => exe,
+        Err(err) => {
+            error!(
+                "Failed to find the running executable, unable to start a client per socket: {err}"
+            );
+            return;
+        }
+    };
+
+    let args: Vec<_> = env::args_os().skip(1).collect();
+
+    let mut signals = Signals::new();
+    let mut rescan = interval(RESCAN_INTERVAL);
+    let mut children: HashMap<PathBuf, Child> = HashMap::new();
+    // the socket of the copy which looks up album art
+    let mut primary: Option<PathBuf> = None;
+
+    loop {
+        tokio::select! {
+            _ = rescan.tick() => {
+                let sockets = discover();
+
+                children.retain(|socket, child| {
+                    if let Ok(Some(status)) = child.try_wait() {
+                        warn!("Client for Discord socket '{}' exited with {status}", socket.display());
+                        return false;
+                    }
+
+                    if sockets.contains(socket) {
+                        return true;
+                    }
+
+                    info!("Discord socket '{}' is gone, stopping its client", socket.display());
+                    forward(child, Signal::Terminate);
+                    false
+                });
+
+                // if the copy looking up album art has stopped, restart another in its place,
+                // which is killed when dropped and started again below
+                if primary.take_if(|socket| !children.contains_key(socket)).is_some()
+                    && let Some(socket) = children.keys().next().cloned()
+                {
+                    debug!("Restarting client for Discord socket '{}' to look up album art", socket.display());
+                    children.remove(&socket);
+                }
+
+                for socket in sockets {
+                    if children.contains_key(&socket) {
+                        continue;
+                    }
+
+                    let mut command = Command::new(&exe);
+                    command.args(&args).env(CHILD_SOCKET, &socket).kill_on_drop(true);
+
+                    if primary.is_some() {
+                        command.env(CHILD_CACHE_ONLY, "1");
+                    }
+
+                    match command.spawn() {
+                        Ok(child) => {
+                            info!("Showing status in Discord socket '{}'", socket.display());
+                            primary.get_or_insert_with(|| socket.clone());
+                            children.insert(socket, child);
+                        }
+                        Err(err) => error!(
+                            "Failed to start client for Discord socket '{}': {err}",
+                            socket.display()
+                        ),
+                    }
+                }
+            }
+            signal = signals.recv() => {
+                for child in children.values() {
+                    forward(child, signal);
+                }
+
+                if signal == Signal::Terminate {
+                    info!("Shutting down");
+                    break;
+                }
+            }
+        }
+    }
+
+    for (socket, mut child) in children {
+        if let Err(err) = child.wait().await {
+            debug!(
+                "Failed to wait for client for Discord socket '{}': {err}",
+                socket.display()
+            );
+        }
+    }
+}
+
+/// Passes a signal on to a child process.
+fn forward(child: &Child, signal: Signal) {
+    let Some(pid) = child.id() else {
+        return;
+    };
+
+    if let Err(err) = signal.send_to(pid) {
+        debug!("Failed to send {signal:?} to process {pid}: {err}");
+    }
+}
@@ -0,0 +1,119 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use tokio::sync::Notify;
+use tracing::{debug, warn};
+
+use crate::reconnect::ErrorKind;
+
+/// Maximum number of events waiting to be handled
+/// before the oldest are dropped.
+const CAPACITY: usize = 16;
+
+#[derive(Debug)]
+pub enum ServiceEvent {
+    Ready,
+    Error(ErrorKind, String),
+    /// A friend's join secret, after clicking "Join" on their status
+    Join(String),
+}
+
+impl ServiceEvent {
+    /// Checks whether an event can replace another without losing anything.
+    ///
+    /// Errors are only merged with errors of the same kind,
+    /// so a fatal error is never hidden by the disconnect which follows it.
+    /// Every join is kept, as each carries a different friend's secret.
+    fn same_kind(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Ready, Self::Ready) => true,
+            (Self::Error(kind, _), Self::Error(other_kind, _)) => kind == other_kind,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    events: Mutex<VecDeque<ServiceEvent>>,
+    notify: Notify,
+}
+
+/// Sends events from the Discord callbacks to the main loop.
+///
+/// Sending never blocks or fails, so it is safe to call from any callback.
+#[derive(Debug, Clone)]
+pub struct EventSender {
+    queue: Arc<Queue>,
+}
+
+pub struct EventReceiver {
+    queue: Arc<Queue>,
+}
+
+/// Creates a connected sender and receiver.
+pub fn channel() -> (EventSender, EventReceiver) {
+    let queue = Arc::new(Queue::default());
+
+    (
+        EventSender {
+            queue: queue.clone(),
+        },
+        EventReceiver { queue },
+    )
+}
+
+impl EventSender {
+    /// Queues an event for the main loop.
+    ///
+    /// An event of the same kind as the most recently queued one
+    /// replaces it rather than being queued again.
+    /// If the queue is full, the oldest event is dropped.
+    pub fn send(&self, event: ServiceEvent) {
+        let mut events = self
+            .queue
+            .events
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        if let Some(last) = events.back_mut()
+            && last.same_kind(&event)
+        {
+            debug!("Coalescing duplicate Discord event: {event:?}");
+            *last = event;
+        } else {
+            if events.len() >= CAPACITY
+                && let Some(dropped) = events.pop_front()
+            {
+                warn!("Discord event queue is full, dropping oldest event: {dropped:?}");
+            }
+
+            events.push_back(event);
+        }
+
+        drop(events);
+        self.queue.notify.notify_one();
+    }
+}
+
+impl EventReceiver {
+    /// Waits for the next event.
+    ///
+    /// This is cancel safe, so can be used inside `tokio::select!`.
+    pub async fn recv(&mut self) -> ServiceEvent {
+        loop {
+            let event = self
+                .queue
+                .events
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .pop_front();
+
+            if let Some(event) = event {
+                return event;
+            }
+
+            self.queue.notify.notified().await;
+        }
+    }
+}
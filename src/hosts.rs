@@ -0,0 +1,273 @@
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+use mpd_client::Client;
+use mpd_client::client::ConnectionEvent;
+use mpd_client::commands;
+use mpd_client::responses::PlayState;
+use tokio::sync::{Notify, mpsc};
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, sleep, timeout};
+use tracing::{debug, error, info, warn};
+
+use crate::config::{Host, HostSelection};
+use crate::mpd_conn::{self, OpenError};
+use crate::reconnect::Backoff;
+
+/// How long to wait for a host to answer before treating it as unreachable,
+/// as a connection which dropped without being closed never answers.
+const STATUS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait for a new pool to connect to its first host.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Connections to every configured MPD host,
+/// each of which is kept open in the background and reconnected when it drops.
+pub struct HostPool {
+    hosts: Vec<Arc<HostConnection>>,
+    selection: HostSelection,
+    /// The host picked last time
+    current: Mutex<Option<usize>>,
+    events: mpsc::UnboundedReceiver<(usize, ConnectionEvent)>,
+    /// Notified whenever a host connects
+    connected: Arc<Notify>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+struct HostConnection {
+    host: Host,
+    client: Mutex<Option<Client>>,
+    /// When the host was first seen playing, if it still is
+    playing_since: Mutex<Option<Instant>>,
+}
+
+impl HostConnection {
+    fn client(&self) -> Option<Client> {
+        self.client
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    fn set_client(&self, client: Option<Client>) {
+        *self.client.lock().unwrap_or_else(PoisonError::into_inner) = client;
+    }
+
+    fn playing_since(&self) -> Option<Instant> {
+        *self
+            .playing_since
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn set_playing(&self, playing: bool) {
+        let mut playing_since = self
+            .playing_since
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        if !playing {
+            *playing_since = None;
+        } else if playing_since.is_none() {
+            *playing_since = Some(Instant::now());
+        }
+    }
+}
+
+impl HostPool {
+    /// Starts connecting to each host,
+    /// waiting `retry_interval` between attempts.
+    pub fn new(hosts: Vec<Host>, selection: HostSelection, retry_interval: Duration) -> Self {
+        let (tx, events) = mpsc::unbounded_channel();
+        let connected = Arc::new(Notify::new());
+
+        let hosts: Vec<_> = hosts
+            .into_iter()
+            .map(|host| {
+                Arc::new(HostConnection {
+                    host,
+                    client: Mutex::new(None),
+                    playing_since: Mutex::new(None),
+                })
+            })
+            .collect();
+
+        let tasks = hosts
+            .iter()
+            .enumerate()
+            .map(|(index, conn)| {
+                tokio::spawn(maintain(
+                    index,
+                    conn.clone(),
+                    tx.clone(),
+                    connected.clone(),
+                    retry_interval,
+                ))
+            })
+            .collect();
+
+        Self {
+            hosts,
+            selection,
+            current: Mutex::new(None),
+            events,
+            connected,
+            tasks,
+        }
+    }
+
+    /// Waits a few seconds for any host to connect,
+    /// returning straight away if one already is.
+    /// Returns whether a host is connected.
+    pub async fn wait_connected(&self) -> bool {
+        if self.hosts.iter().any(|conn| conn.client().is_some()) {
+            return true;
+        }
+
+        timeout(CONNECT_TIMEOUT, self.connected.notified())
+            .await
+            .is_ok()
+    }
+
+    /// Waits for the next event from any host,
+    /// along with the index of the host it came from.
+    ///
+    /// This is cancel safe, so can be used inside `tokio::select!`.
+    pub async fn recv(&mut self) -> Option<(usize, ConnectionEvent)> {
+        self.events.recv().await
+    }
+
+    /// Picks the host to show the status of.
+    ///
+    /// When more than one host is playing, the selection policy decides between them.
+    /// If none are playing, the first connected host is used,
+    /// or the current one for policies other than [`HostSelection::Priority`].
+    pub async fn active(&self) -> Option<(usize, Client)> {
+        let mut connected = vec![];
+
+        for (index, conn) in self.hosts.iter().enumerate() {
+            let Some(client) = conn.client() else {
+                conn.set_playing(false);
+                continue;
+            };
+
+            match timeout(STATUS_TIMEOUT, client.command(commands::Status)).await {
+                Ok(Ok(status)) => {
+                    let playing = matches!(status.state, PlayState::Playing);
+                    conn.set_playing(playing);
+                    connected.push((index, playing));
+                }
+                Ok(Err(err)) => debug!(
+                    "Failed to get status of MPD host '{}': {err:?}",
+                    conn.host.name
+                ),
+                Err(_) => debug!("MPD host '{}' did not answer in time", conn.host.name),
+            }
+        }
+
+        let mut current = self.current.lock().unwrap_or_else(PoisonError::into_inner);
+        let is_current = |index: &usize| Some(*index) == *current;
+
+        let playing = connected
+            .iter()
+            .filter(|(_, playing)| *playing)
+            .map(|(index, _)| *index);
+
+        let selected = match self.selection {
+            HostSelection::Priority => playing.min(),
+            HostSelection::MostRecent => {
+                playing.max_by_key(|index| self.hosts[*index].playing_since())
+            }
+            HostSelection::Sticky => playing.clone().find(is_current).or_else(|| playing.min()),
+        }
+        .or_else(|| {
+            let mut connected = connected.iter().map(|(index, _)| *index);
+
+            if matches!(self.selection, HostSelection::Priority) {
+                connected.next()
+            } else {
+                connected
+                    .clone()
+                    .find(is_current)
+                    .or_else(|| connected.next())
+            }
+        })?;
+
+        if !is_current(&selected) {
+            debug!("Selected MPD host '{}'", self.hosts[selected].host.name);
+        }
+
+        *current = Some(selected);
+        drop(current);
+
+        self.hosts[selected]
+            .client()
+            .map(|client| (selected, client))
+    }
+}
+
+impl Drop for HostPool {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// Keeps a host connected, forwarding its events to the pool.
+async fn maintain(
+    index: usize,
+    conn: Arc<HostConnection>,
+    tx: mpsc::UnboundedSender<(usize, ConnectionEvent)>,
+    connected: Arc<Notify>,
+    retry_interval: Duration,
+) {
+    let name = &conn.host.name;
+
+    // wrong passwords are retried increasingly slowly,
+    // as they are unlikely to fix themselves
+    let mut auth_backoff = Backoff::new(retry_interval);
+    let mut reported_auth_failure = false;
+
+    loop {
+        let mut delay = retry_interval;
+
+        match mpd_conn::open(&conn.host).await {
+            Ok((client, mut events)) => {
+                info!("Connected to MPD host '{name}'");
+                conn.set_client(Some(client));
+                connected.notify_one();
+                auth_backoff.reset();
+                reported_auth_failure = false;
+
+                while let Some(event) = events.next().await {
+                    let closed = matches!(event, ConnectionEvent::ConnectionClosed(_));
+
+                    if tx.send((index, event)).is_err() {
+                        return;
+                    }
+
+                    if closed {
+                        break;
+                    }
+                }
+
+                conn.set_client(None);
+                warn!("Lost connection to MPD host '{name}'");
+            }
+            Err(OpenError::IncorrectPassword) => {
+                delay = auth_backoff.next_delay();
+
+                if reported_auth_failure {
+                    debug!("MPD host '{name}' still rejects the password, retrying in {delay:?}");
+                } else {
+                    error!("MPD host '{name}' rejected the password, check the config");
+                    reported_auth_failure = true;
+                }
+            }
+            Err(err) => debug!("Failed to connect to MPD host '{name}': {err}"),
+        }
+
+        sleep(delay).await;
+    }
+}
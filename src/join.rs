@@ -0,0 +1,236 @@
+use std::time::Duration;
+
+use mpd_client::Client;
+use mpd_client::commands::{self, Find};
+use mpd_client::filter::Filter;
+use mpd_client::responses::{Song, Status};
+use mpd_client::tag::Tag;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::mpd_conn::{now, try_get_first_tag};
+
+/// Maximum length of a Discord join secret.
+const MAX_SECRET_BYTES: usize = 128;
+
+/// The track and playback position shared with friends
+/// through the activity's join secret.
+///
+/// Field names are kept short to fit within the secret length limit.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JoinSecret {
+    /// MusicBrainz recording ID
+    #[serde(rename = "m", default, skip_serializing_if = "Option::is_none")]
+    mbid: Option<String>,
+    #[serde(rename = "a", default, skip_serializing_if = "Option::is_none")]
+    artist: Option<String>,
+    #[serde(rename = "l", default, skip_serializing_if = "Option::is_none")]
+    album: Option<String>,
+    #[serde(rename = "t", default, skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    /// Unix timestamp the track started playing at,
+    /// so the position stays correct however long after rendering it is used.
+    #[serde(rename = "s")]
+    started: u64,
+}
+
+impl JoinSecret {
+    /// Builds the join secret for the playing song.
+    ///
+    /// Returns `None` if the song has neither a MusicBrainz ID
+    /// nor a title to search for.
+    pub fn new(song: &Song, status: &Status) -> Option<Self> {
+        let tag = |tag: Tag| try_get_first_tag(song.tags.get(&tag)).map(str::to_string);
+
+        let mbid = tag(Tag::MusicBrainzRecordingId);
+
+        // the ID alone is enough to find the track, and keeps the secret short
+        let (artist, album, title) = if mbid.is_some() {
+            (None, None, None)
+        } else {
+            (
+                tag(Tag::Artist),
+                tag(Tag::Album),
+                Some(song.title()?.to_string()),
+            )
+        };
+
+        let elapsed = status.elapsed.unwrap_or_default().as_secs();
+
+        Some(Self {
+            mbid,
+            artist,
+            album,
+            title,
+            started: now().saturating_sub(elapsed),
+        })
+    }
+
+    /// Encodes the secret to send to Discord.
+    ///
+    /// The album is left out if the secret would otherwise be too long.
+    /// Returns `None` if it still does not fit.
+    pub fn encode(mut self) -> Option<String> {
+        let encode = |secret: &Self| serde_json::to_string(secret).ok();
+
+        let mut secret = encode(&self)?;
+        if secret.len() > MAX_SECRET_BYTES && self.album.take().is_some() {
+            secret = encode(&self)?;
+        }
+
+        (secret.len() <= MAX_SECRET_BYTES).then_some(secret)
+    }
+
+    /// Decodes a secret received from Discord.
+    pub fn decode(secret: &str) -> Result<Self, String> {
+        serde_json::from_str(secret).map_err(|err| format!("Invalid join secret: {err}"))
+    }
+
+    fn filter(&self) -> Option<Filter> {
+        if let Some(mbid) = &self.mbid {
+            return Some(Filter::tag(Tag::MusicBrainzRecordingId, mbid));
+        }
+
+        let mut filter = Filter::tag(Tag::Title, self.title.as_ref()?);
+
+        if let Some(artist) = &self.artist {
+            filter = filter.and(Filter::tag(Tag::Artist, artist));
+        }
+
+        if let Some(album) = &self.album {
+            filter = filter.and(Filter::tag(Tag::Album, album));
+        }
+
+        Some(filter)
+    }
+}
+
+impl std::fmt::Display for JoinSecret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.mbid, &self.artist, &self.title) {
+            (Some(mbid), _, _) => write!(f, "recording {mbid}"),
+            (None, Some(artist), Some(title)) => write!(f, "'{artist} - {title}'"),
+            (None, None, Some(title)) => write!(f, "'{title}'"),
+            _ => write!(f, "unknown track"),
+        }
+    }
+}
+
+/// Finds the track from a friend's join secret in the MPD database,
+/// adds it to the queue and plays it from the same position.
+pub async fn join(client: &Client, secret: &JoinSecret) -> Result<(), String> {
+    let filter = secret
+        .filter()
+        .ok_or_else(|| "Join secret does not identify a track".to_string())?;
+
+    let songs = client
+        .command(Find::new(filter))
+        .await
+        .map_err(|err| format!("Failed to search MPD for {secret}: {err}"))?;
+
+    let Some(song) = songs.into_iter().next() else {
+        return Err(format!(
+            "Could not find {secret} in the MPD database, unable to join"
+        ));
+    };
+
+    let id = client
+        .command(commands::Add::uri(&song.url))
+        .await
+        .map_err(|err| format!("Failed to add '{}' to the queue: {err}", song.url))?;
+
+    let mut position = Duration::from_secs(now().saturating_sub(secret.started));
+    if song.duration.is_some_and(|duration| position >= duration) {
+        warn!("Friend has already finished {secret}, playing it from the start");
+        position = Duration::ZERO;
+    }
+
+    client
+        .command(commands::SeekTo(id.into(), position))
+        .await
+        .map_err(|err| format!("Failed to play '{}': {err}", song.url))?;
+
+    info!("Joined friend playing {secret} at {position:?}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secret(title: &str, album: &str) -> JoinSecret {
+        JoinSecret {
+            mbid: None,
+            artist: Some("Artist".to_string()),
+            album: Some(album.to_string()),
+            title: Some(title.to_string()),
+            started: 1_700_000_000,
+        }
+    }
+
+    #[test]
+    fn encode_and_decode_round_trip() {
+        let encoded = secret("Title", "Album")
+            .encode()
+            .expect("secret should fit");
+        let decoded = JoinSecret::decode(&encoded).expect("secret should decode");
+
+        assert_eq!(decoded.artist.as_deref(), Some("Artist"));
+        assert_eq!(decoded.album.as_deref(), Some("Album"));
+        assert_eq!(decoded.title.as_deref(), Some("Title"));
+        assert_eq!(decoded.started, 1_700_000_000);
+    }
+
+    #[test]
+    fn encode_leaves_out_missing_fields() {
+        let secret = JoinSecret {
+            mbid: Some("b1a9c0e9-d987-4042-ae91-78d6a3267d69".to_string()),
+            artist: None,
+            album: None,
+            title: None,
+            started: 0,
+        };
+
+        assert_eq!(
+            secret.encode().as_deref(),
+            Some(r#"{"m":"b1a9c0e9-d987-4042-ae91-78d6a3267d69","s":0}"#)
+        );
+    }
+
+    #[test]
+    fn encode_drops_album_when_too_long() {
+        let encoded = secret("Title", &"a".repeat(MAX_SECRET_BYTES))
+            .encode()
+            .expect("secret should fit without the album");
+
+        assert!(encoded.len() <= MAX_SECRET_BYTES);
+        assert_eq!(JoinSecret::decode(&encoded).unwrap().album, None);
+    }
+
+    #[test]
+    fn encode_fails_when_title_too_long() {
+        assert!(
+            secret(&"t".repeat(MAX_SECRET_BYTES), "Album")
+                .encode()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn decode_rejects_invalid_secrets() {
+        assert!(JoinSecret::decode("not json").is_err());
+        assert!(JoinSecret::decode(r#"{"t":"Title"}"#).is_err());
+    }
+
+    #[test]
+    fn display_describes_track() {
+        assert_eq!(secret("Title", "Album").to_string(), "'Artist - Title'");
+
+        let mut secret = secret("Title", "Album");
+        secret.artist = None;
+        assert_eq!(secret.to_string(), "'Title'");
+
+        secret.mbid = Some("id".to_string());
+        assert_eq!(secret.to_string(), "recording id");
+    }
+}
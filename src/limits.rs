@@ -0,0 +1,248 @@
+use reqwest::Url;
+use unicode_segmentation::UnicodeSegmentation;
+
+// https://discord.com/developers/docs/events/gateway-events#activity-object
+
+/// Maximum length of the details, state and hover text fields.
+pub const TEXT_MAX_BYTES: usize = 128;
+/// Minimum length of the details and state fields, if they are set.
+pub const TEXT_MIN_CHARS: usize = 2;
+/// Maximum length of an image asset key or URL.
+pub const IMAGE_MAX_BYTES: usize = 256;
+/// Maximum length of a button label.
+pub const BUTTON_LABEL_MAX_BYTES: usize = 32;
+/// Maximum length of a button URL.
+pub const BUTTON_URL_MAX_BYTES: usize = 512;
+
+/// Truncates a string to a maximum length (byte count).
+///
+/// If the string is too long, whole grapheme clusters are removed from the end
+/// and the ellipsis is appended, so the result is equal to or just below the max.
+/// If the ellipsis does not fit on its own, it is left out.
+pub fn truncate(str: String, max_bytes: usize, ellipsis: &str) -> String {
+    if str.len() <= max_bytes {
+        return str;
+    }
+
+    let ellipsis = if ellipsis.len() < max_bytes {
+        ellipsis
+    } else {
+        ""
+    };
+
+    let mut len = 0;
+    for (i, grapheme) in str.grapheme_indices(true) {
+        if i + grapheme.len() > max_bytes - ellipsis.len() {
+            break;
+        }
+        len = i + grapheme.len();
+    }
+
+    let mut truncated = str[..len].to_string();
+    truncated.push_str(ellipsis);
+    truncated
+}
+
+/// Pads a string with zero-width spaces until it meets the minimum length.
+///
+/// See <https://en.wikipedia.org/wiki/Zero-width_space>
+pub fn pad(mut str: String, min_chars: usize) -> String {
+    while str.chars().count() < min_chars {
+        str.push('\u{200B}');
+    }
+
+    str
+}
+
+/// Checks whether a button URL can be sent to Discord.
+///
+/// It must be an absolute `http` or `https` URL within the length limit.
+pub fn is_valid_url(url: &str) -> bool {
+    url.len() <= BUTTON_URL_MAX_BYTES
+        && Url::parse(url).is_ok_and(|url| matches!(url.scheme(), "http" | "https"))
+}
+
+/// The value of a formatting token,
+/// and the number of times it appears in a format string.
+#[derive(Debug)]
+pub struct TokenValue {
+    pub token: String,
+    pub value: String,
+    pub count: usize,
+}
+
+/// Shortens token values until a format string rendered with them
+/// fits within the max length (byte count).
+///
+/// Tokens are shortened one at a time in the given order,
+/// and any tokens missing from it are never shortened.
+/// If the order is empty, the longest values are shortened first.
+///
+/// Literal text is left untouched, so the rendered string may still be too long
+/// if shortening the tokens is not enough.
+pub fn shorten_tokens(
+    values: &mut [TokenValue],
+    rendered_len: usize,
+    max_bytes: usize,
+    ellipsis: &str,
+    order: &[String],
+) {
+    let mut indices: Vec<usize> = if order.is_empty() {
+        (0..values.len()).collect()
+    } else {
+        order
+            .iter()
+            .filter_map(|token| {
+                let token = token.trim_start_matches('$');
+                values.iter().position(|value| value.token == token)
+            })
+            .collect()
+    };
+
+    if order.is_empty() {
+        indices.sort_by_key(|&i| std::cmp::Reverse(values[i].value.len()));
+    }
+
+    let mut len = rendered_len;
+
+    for i in indices {
+        if len <= max_bytes {
+            break;
+        }
+
+        let value = &mut values[i];
+        let excess = (len - max_bytes).div_ceil(value.count);
+        let old_len = value.value.len();
+
+        value.value = truncate(
+            std::mem::take(&mut value.value),
+            old_len.saturating_sub(excess),
+            ellipsis,
+        );
+
+        len -= (old_len - value.value.len()) * value.count;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(token: &str, value: &str, count: usize) -> TokenValue {
+        TokenValue {
+            token: token.to_string(),
+            value: value.to_string(),
+            count,
+        }
+    }
+
+    #[test]
+    fn truncate_leaves_short_strings() {
+        assert_eq!(truncate("hello".to_string(), 5, "…"), "hello");
+        assert_eq!(truncate(String::new(), 0, "…"), "");
+    }
+
+    #[test]
+    fn truncate_appends_ellipsis_within_limit() {
+        // the ellipsis is three bytes, leaving five for the text
+        assert_eq!(truncate("hello world".to_string(), 8, "…"), "hello…");
+    }
+
+    #[test]
+    fn truncate_keeps_graphemes_whole() {
+        // "e\u{301}" is one grapheme of three bytes
+        let str = "ae\u{301}b".to_string();
+
+        assert_eq!(truncate(str.clone(), 4, "."), "a.");
+        assert_eq!(truncate(str.clone(), 5, "."), str);
+
+        let flag = "🇬🇧🇫🇷".to_string();
+        assert_eq!(truncate(flag, 12, ""), "🇬🇧");
+    }
+
+    #[test]
+    fn truncate_never_exceeds_limit() {
+        let str = "ae\u{301}🇬🇧 hello".to_string();
+
+        for max_bytes in 0..=str.len() {
+            let truncated = truncate(str.clone(), max_bytes, "…");
+            assert!(
+                truncated.len() <= max_bytes,
+                "{truncated:?} is longer than {max_bytes}"
+            );
+        }
+    }
+
+    #[test]
+    fn truncate_drops_ellipsis_longer_than_limit() {
+        assert_eq!(truncate("hello".to_string(), 2, "..."), "he");
+        assert_eq!(truncate("hello".to_string(), 3, "..."), "hel");
+        assert_eq!(truncate("hello".to_string(), 4, "..."), "h...");
+    }
+
+    #[test]
+    fn pad_fills_to_minimum() {
+        assert_eq!(pad("a".to_string(), 2), "a\u{200B}");
+        assert_eq!(pad("ab".to_string(), 2), "ab");
+    }
+
+    #[test]
+    fn shorten_tokens_accounts_for_repeated_tokens() {
+        // "$title - $title"
+        let mut values = [value("title", "abcdefghij", 2)];
+        shorten_tokens(&mut values, 23, 17, ".", &[]);
+
+        assert_eq!(values[0].value, "abcdef.");
+        assert!(values[0].value.len() * 2 + 3 <= 17);
+    }
+
+    #[test]
+    fn shorten_tokens_follows_order() {
+        let mut values = [
+            value("artist", "aaaaaaaaaa", 1),
+            value("title", "tttttttttt", 1),
+        ];
+        let order = ["$title".to_string(), "artist".to_string()];
+        shorten_tokens(&mut values, 20, 16, "", &order);
+
+        assert_eq!(values[0].value, "aaaaaaaaaa");
+        assert_eq!(values[1].value, "tttttt");
+    }
+
+    #[test]
+    fn shorten_tokens_moves_on_to_next_token() {
+        let mut values = [value("artist", "aaaa", 1), value("title", "tttt", 1)];
+        let order = ["title".to_string(), "artist".to_string()];
+        shorten_tokens(&mut values, 8, 2, "", &order);
+
+        assert_eq!(values[0].value, "aa");
+        assert_eq!(values[1].value, "");
+    }
+
+    #[test]
+    fn shorten_tokens_skips_tokens_missing_from_order() {
+        let mut values = [value("artist", "aaaaaaaaaa", 1), value("title", "tttt", 1)];
+        let order = ["title".to_string()];
+        shorten_tokens(&mut values, 14, 5, "", &order);
+
+        assert_eq!(values[0].value, "aaaaaaaaaa");
+        assert_eq!(values[1].value, "");
+    }
+
+    #[test]
+    fn shorten_tokens_defaults_to_longest_first() {
+        let mut values = [value("artist", "aaaa", 1), value("title", "tttttttttt", 1)];
+        shorten_tokens(&mut values, 14, 10, "", &[]);
+
+        assert_eq!(values[0].value, "aaaa");
+        assert_eq!(values[1].value, "tttttt");
+    }
+
+    #[test]
+    fn shorten_tokens_leaves_fitting_values() {
+        let mut values = [value("title", "title", 1)];
+        shorten_tokens(&mut values, 5, 10, "…", &[]);
+
+        assert_eq!(values[0].value, "title");
+    }
+}
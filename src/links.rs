@@ -0,0 +1,85 @@
+use mpd_client::responses::Song;
+use mpd_client::tag::Tag;
+use reqwest::Url;
+
+use crate::mpd_conn::try_get_first_tag;
+
+/// Names of the preset button links,
+/// used in place of a URL as `@name`.
+pub const PRESETS: &[&str] = &[
+    "musicbrainz_release",
+    "musicbrainz_recording",
+    "youtube_search",
+    "bandcamp_search",
+    "wikipedia_artist",
+    "listenbrainz_user",
+];
+
+/// Builds the URL for a preset button link.
+///
+/// Returns `None` if the preset is unknown,
+/// or the current song is missing the tags needed to build it.
+pub fn get_preset_url(preset: &str, song: &Song, listenbrainz_user: &str) -> Option<String> {
+    let tags = &song.tags;
+    let artist = try_get_first_tag(tags.get(&Tag::Artist))
+        .or(try_get_first_tag(tags.get(&Tag::AlbumArtist)));
+
+    let search = || match (artist, song.title()) {
+        (Some(artist), Some(title)) => Some(format!("{artist} {title}")),
+        (None, Some(title)) => Some(title.to_string()),
+        _ => None,
+    };
+
+    let url = match preset {
+        "musicbrainz_release" => with_path(
+            "https://musicbrainz.org",
+            &[
+                "release",
+                try_get_first_tag(tags.get(&Tag::MusicBrainzReleaseId))?,
+            ],
+        ),
+        "musicbrainz_recording" => with_path(
+            "https://musicbrainz.org",
+            &[
+                "recording",
+                try_get_first_tag(tags.get(&Tag::MusicBrainzRecordingId))?,
+            ],
+        ),
+        "youtube_search" => with_query(
+            "https://www.youtube.com/results",
+            "search_query",
+            &search()?,
+        ),
+        "bandcamp_search" => with_query("https://bandcamp.com/search", "q", &search()?),
+        "wikipedia_artist" => {
+            let mut url = with_query(
+                "https://en.wikipedia.org/wiki/Special:Search",
+                "search",
+                artist?,
+            )?;
+            // jump straight to the article if there is an exact match
+            url.query_pairs_mut().append_pair("go", "Go");
+            Some(url)
+        }
+        "listenbrainz_user" if !listenbrainz_user.is_empty() => {
+            with_path("https://listenbrainz.org", &["user", listenbrainz_user])
+        }
+        _ => None,
+    }?;
+
+    Some(url.to_string())
+}
+
+/// Appends percent-encoded path segments to a base URL.
+fn with_path(base: &str, segments: &[&str]) -> Option<Url> {
+    let mut url = Url::parse(base).ok()?;
+    url.path_segments_mut().ok()?.extend(segments);
+    Some(url)
+}
+
+/// Adds a percent-encoded query parameter to a base URL.
+fn with_query(base: &str, key: &str, value: &str) -> Option<Url> {
+    let mut url = Url::parse(base).ok()?;
+    url.query_pairs_mut().append_pair(key, value);
+    Some(url)
+}
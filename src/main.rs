@@ -1,30 +1,61 @@
 use std::time::Duration;
 
+use clap::Parser;
 use discord_presence::models::ActivityButton;
 use discord_presence::models::EventData;
 use discord_presence::models::{ActivityType, DisplayType};
-use discord_presence::{Client as DiscordClient, DiscordError};
+use discord_presence::{Client as DiscordClient, DiscordError, Event};
 use mpd_client::client::ConnectionEvent::SubsystemChange;
 use mpd_client::client::Subsystem;
 use mpd_client::commands;
 use mpd_client::responses::{PlayState, Song, SongInQueue, Status};
-use mpd_utils::MultiHostClient;
 use regex::Regex;
-use tokio::sync::mpsc;
-use tokio::time::sleep;
-use tracing::{debug, error, info};
+use tokio::runtime::Runtime;
+use tokio::time::{Instant, Interval, MissedTickBehavior, interval, sleep};
+use tracing::{debug, error, info, warn};
 
+use crate::activity::Activity;
 use crate::album_art::AlbumArtClient;
-use crate::config::DisplayType as ConfigDisplayType;
-use crate::mpd_conn::get_timestamp;
+use crate::cli::{Cli, Command};
+use crate::config::{
+    ActivityType as ConfigActivityType, AlbumArtConfig, DisplayType as ConfigDisplayType, Format,
+    LoadOptions, default_image,
+};
+use crate::config_watch::ConfigWatcher;
+use crate::discord_socket::Redirect;
+use crate::events::{EventSender, ServiceEvent};
+use crate::hosts::HostPool;
+use crate::join::JoinSecret;
+use crate::limits::{BUTTON_LABEL_MAX_BYTES, TEXT_MAX_BYTES, TokenValue};
+use crate::mpd_conn::{Timestamps, get_party_size, get_timestamp};
+use crate::reconnect::{Backoff, ErrorKind};
+use crate::signals::{Signal, Signals};
 use config::Config;
 
+mod activity;
 mod album_art;
+mod cache;
+mod cache_cli;
+mod cli;
 mod config;
+mod config_watch;
+mod discord_socket;
+mod events;
+mod hosts;
+mod join;
+mod limits;
+mod links;
 mod mpd_conn;
+mod reconnect;
+mod render;
+mod signals;
+mod warm_cache;
 
 pub const IDLE_TIME: u64 = 3;
 
+/// Special `small_image` value which is replaced with a photo of the artist
+const ARTIST_IMAGE_TOKEN: &str = "$artist_image";
+
 fn map_display_type(display_type: ConfigDisplayType) -> DisplayType {
     match display_type {
         ConfigDisplayType::Name => DisplayType::Name,
@@ -33,6 +64,15 @@ fn map_display_type(display_type: ConfigDisplayType) -> DisplayType {
     }
 }
 
+fn map_activity_type(activity_type: ConfigActivityType) -> ActivityType {
+    match activity_type {
+        ConfigActivityType::Listening => ActivityType::Listening,
+        ConfigActivityType::Playing => ActivityType::Playing,
+        ConfigActivityType::Watching => ActivityType::Watching,
+        ConfigActivityType::Competing => ActivityType::Competing,
+    }
+}
+
 struct Tokens {
     details: Vec<String>,
     state: Vec<String>,
@@ -42,154 +82,500 @@ struct Tokens {
     button1_link: Vec<String>,
     button2_text: Vec<String>,
     button2_link: Vec<String>,
+    /// Compiled patterns for each rule, by index
+    rules: Vec<(usize, Regex)>,
 }
 
-#[tokio::main]
-async fn main() {
-    tracing_subscriber::fmt::init();
+impl Tokens {
+    fn new(format: &Format) -> Self {
+        let re = Regex::new(r"\$(\w+)").expect("Failed to parse regex");
 
-    let re = Regex::new(r"\$(\w+)").expect("Failed to parse regex");
+        for link in [&format.button1_link, &format.button2_link] {
+            if let Some(preset) = link.strip_prefix('@')
+                && !links::PRESETS.contains(&preset)
+            {
+                warn!("Unknown button link preset '{link}', the button will be hidden");
+            }
+        }
 
-    let config = Config::load();
-    let format = &config.format;
+        Self {
+            details: get_tokens(&re, &format.details),
+            state: get_tokens(&re, &format.state),
+            large_text: get_tokens(&re, &format.large_text),
+            small_text: get_tokens(&re, &format.small_text),
+            button1_text: get_tokens(&re, &format.button1_text),
+            button1_link: get_tokens(&re, &format.button1_link),
+            button2_text: get_tokens(&re, &format.button2_text),
+            button2_link: get_tokens(&re, &format.button2_link),
+            rules: format
+                .rules
+                .iter()
+                .enumerate()
+                .filter_map(|(i, rule)| match Regex::new(&rule.matches) {
+                    Ok(re) => Some((i, re)),
+                    Err(err) => {
+                        error!("Invalid pattern for rule {}, ignoring it: {err}", i + 1);
+                        None
+                    }
+                })
+                .collect(),
+        }
+    }
 
-    let tokens = Tokens {
-        details: get_tokens(&re, &format.details),
-        state: get_tokens(&re, &format.state),
-        large_text: get_tokens(&re, &format.large_text),
-        small_text: get_tokens(&re, &format.small_text),
-        button1_text: get_tokens(&re, &format.button1_text),
-        button1_link: get_tokens(&re, &format.button1_link),
-        button2_text: get_tokens(&re, &format.button2_text),
-        button2_link: get_tokens(&re, &format.button2_link),
-    };
+    /// Picks the activity and display type for a song,
+    /// applying the first rule which matches it.
+    fn get_types(
+        &self,
+        format: &Format,
+        song: &Song,
+        status: &Status,
+        host: &str,
+    ) -> (ConfigActivityType, ConfigDisplayType) {
+        let rule = self
+            .rules
+            .iter()
+            .map(|(i, re)| (&format.rules[*i], re))
+            .find(|(rule, re)| {
+                let token = rule.token.trim_start_matches('$');
+                re.is_match(&mpd_conn::get_token_value(song, status, host, token))
+            })
+            .map(|(rule, _)| rule);
+
+        let activity_type = rule
+            .and_then(|rule| rule.activity_type)
+            .unwrap_or(format.activity_type);
+
+        let display_type = rule
+            .and_then(|rule| rule.display_type)
+            .or_else(|| format.display_types.get(activity_type))
+            .unwrap_or(format.display_type);
+
+        (activity_type, display_type)
+    }
+}
 
-    // MPD and Discord connections
-    let mut mpd = MultiHostClient::new(config.hosts.clone(), Duration::from_secs(IDLE_TIME));
-    mpd.init();
+fn main() {
+    let cli = Cli::parse();
 
-    let (tx, mut rx) = mpsc::channel(16);
-    let mut service = Service::new(&config, tokens, tx);
+    tracing_subscriber::fmt()
+        .with_max_level(cli.log_level)
+        .init();
+
+    if cli.print_default_config {
+        match Config::default_toml() {
+            Ok(toml) => print!("{toml}"),
+            Err(err) => {
+                eprintln!("Failed to serialize the default config: {err}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let options = cli.load_options();
+
+    match cli.command {
+        Some(Command::Version) => {
+            println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+        }
+        Some(Command::Cache(command)) => {
+            if let Err(err) = cache_cli::run(command) {
+                eprintln!("{err}");
+                std::process::exit(1);
+            }
+        }
+        Some(Command::CheckConfig) => match Config::read(&options) {
+            Ok(_) => println!("Config file '{}' is valid", options.path.display()),
+            Err(err) => {
+                eprintln!("{err}");
+                std::process::exit(1);
+            }
+        },
+        Some(Command::Render) => {
+            let config = load_config(&options);
+            if let Err(err) = runtime().block_on(render::run(config)) {
+                eprintln!("{err}");
+                std::process::exit(1);
+            }
+        }
+        Some(Command::WarmCache) => {
+            let config = load_config(&options);
+            runtime().block_on(warm_cache::run(&config));
+        }
+        None => {
+            let config = load_config(&options);
+
+            let socket = discord_socket::setting(&config.discord_socket);
+
+            if socket == discord_socket::ALL {
+                runtime().block_on(discord_socket::run_all());
+                return;
+            }
+
+            // must happen before the runtime starts its worker threads
+            let redirect = match discord_socket::select(&socket) {
+                Ok(redirect) => redirect,
+                Err(err) => {
+                    error!("{err}");
+                    None
+                }
+            };
+
+            runtime().block_on(run(config, options, redirect));
+        }
+    }
+}
+
+/// Loads the config, exiting if it is invalid.
+fn load_config(options: &LoadOptions) -> Config {
+    match Config::load(options) {
+        Ok(config) => config,
+        Err(err) => {
+            error!("{err}");
+            std::process::exit(1);
+        }
+    }
+}
+
+fn runtime() -> Runtime {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Failed to create async runtime")
+}
+
+async fn run(config: Config, options: LoadOptions, mut redirect: Option<Redirect>) {
+    // MPD and Discord connections
+    let mut mpd = HostPool::new(
+        config.hosts.clone(),
+        config.host_selection,
+        Duration::from_secs(IDLE_TIME),
+    );
+
+    let (tx, mut rx) = events::channel();
+    let mut service = Service::new(config, tx);
     service.start();
 
+    let mut signals = Signals::new();
+
+    let mut config_watcher = ConfigWatcher::new(&options.path);
+
+    let mut watchdog = create_watchdog(service.config.mpd_offline.check_interval);
+    let mut mpd_online = true;
+
+    let mut backoff = Backoff::new(Duration::from_secs(IDLE_TIME));
+    let reconnect = sleep(Duration::ZERO);
+    tokio::pin!(reconnect);
+    let mut reconnecting = false;
+    let mut reported_not_running = false;
+
     loop {
         tokio::select! {
-            Ok(event) = mpd.recv() => {
-                if matches!(*event, SubsystemChange(Subsystem::Player | Subsystem::Queue)) {
+            Some((_, event)) = mpd.recv() => {
+                if matches!(event, SubsystemChange(Subsystem::Player | Subsystem::Queue)) {
                     info!("Detected change, updating status");
                     debug!("Change: {event:?}");
 
-                    if let Ok((Some(status), current_song)) = mpd.with_client(|client| async move {
-                        let status = client.command(commands::Status).await.ok();
-
-                        let current_song = if status.is_some() {
-                            client.command(commands::CurrentSong).await.ok().flatten()
-                        } else {
-                            None
-                        };
-
-                        (status, current_song)
-                    }).await {
-                        service.update_state(&status, current_song).await;
-                    }
+                    refresh(&mpd, &mut service).await;
                 }
             }
-            Some(event) = rx.recv() => {
+            event = rx.recv() => {
                 match event {
                     ServiceEvent::Ready => {
                         info!("Connected to Discord");
-
-                        // set initial status as soon as ready
-                        if let Ok((Some(status), current_song)) = mpd.with_client(|client| async move {
-                            let status = client.command(commands::Status).await.ok();
-
-                            let current_song = if status.is_some() {
-                                client.command(commands::CurrentSong).await.ok().flatten()
-                            } else {
-                                None
-                            };
-
-                            (status, current_song)
-                        }).await {
-                            service.update_state(&status, current_song).await;
+                        backoff.reset();
+                        reported_not_running = false;
+                        service.subscribe_join();
+
+                        // set initial status as soon as ready,
+                        // only going back to MPD if the last status is out of date
+                        if !mpd_online {
+                            service.set_offline();
+                        } else if !service.replay() {
+                            refresh(&mpd, &mut service).await;
                         }
                     },
-                    ServiceEvent::Error(err) => {
-                        error!("{err}");
-                        sleep(Duration::from_secs(IDLE_TIME)).await;
-                        service.start();
+                    ServiceEvent::Join(secret) => join(&mpd, &secret).await,
+                    ServiceEvent::Error(ErrorKind::Fatal, err) => {
+                        error!("Discord rejected the connection, not reconnecting: {err}");
+                        service.rejected = true;
+                        reconnecting = false;
+                    }
+                    // the client disconnects after being rejected,
+                    // which must not start reconnecting
+                    ServiceEvent::Error(_, err) if service.rejected => {
+                        debug!("Ignoring Discord error after the connection was rejected: {err}");
+                    }
+                    ServiceEvent::Error(kind, err) => {
+                        let delay = backoff.next_delay();
+
+                        if kind == ErrorKind::NotRunning && reported_not_running {
+                            debug!("Discord still not running, retrying in {delay:?}: {err}");
+                        } else if kind == ErrorKind::NotRunning {
+                            info!("Discord does not appear to be running, will keep retrying in the background");
+                            debug!("{err}");
+                            reported_not_running = true;
+                        } else {
+                            warn!("Lost connection to Discord, retrying in {delay:?}: {err}");
+                        }
+
+                        reconnect.as_mut().reset(Instant::now() + delay);
+                        reconnecting = true;
                     }
                 }
             },
+            () = &mut reconnect, if reconnecting => {
+                reconnecting = false;
+
+                if let Some(redirect) = &mut redirect {
+                    redirect.relink();
+                }
+                service.start();
+            }
+            _ = watchdog.tick() => {
+                let reachable = mpd.active().await.is_some();
+
+                if mpd_online && !reachable {
+                    warn!("Lost connection to MPD");
+                    mpd_online = false;
+                    service.set_offline();
+                } else if !mpd_online && reachable {
+                    info!("Reconnected to MPD");
+                    mpd_online = true;
+                    refresh(&mpd, &mut service).await;
+                }
+            }
+            () = config_watcher.changed() => {
+                info!("Config file changed, reloading");
+                reload(&options, &mut mpd, &mut service, &mut watchdog, mpd_online).await;
+            }
+            signal = signals.recv() => {
+                match signal {
+                    Signal::Terminate => {
+                        info!("Shutting down");
+                        break;
+                    }
+                    Signal::Reload => {
+                        info!("Reloading config");
+                        reload(&options, &mut mpd, &mut service, &mut watchdog, mpd_online).await;
+                    }
+                    Signal::Toggle => {
+                        if service.toggle() && !service.replay() {
+                            refresh(&mpd, &mut service).await;
+                        }
+                    }
+                }
+            }
         }
     }
+
+    service.shutdown();
 }
 
-enum ServiceEvent {
-    Ready,
-    Error(String),
+/// Creates the timer for checking whether MPD is still reachable.
+fn create_watchdog(check_interval: u64) -> Interval {
+    let mut watchdog = interval(Duration::from_secs(check_interval.max(1)));
+    watchdog.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    watchdog
 }
 
-struct Service<'a> {
-    config: &'a Config,
+/// Reads the config file again and swaps it in if it is valid,
+/// keeping the current config otherwise.
+///
+/// MPD hosts are only reconnected to if they have changed,
+/// and the status is re-rendered straight away with the new config.
+async fn reload(
+    options: &LoadOptions,
+    mpd: &mut HostPool,
+    service: &mut Service,
+    watchdog: &mut Interval,
+    mpd_online: bool,
+) {
+    let config = match Config::read(options) {
+        Ok(config) => config,
+        Err(err) => {
+            error!("{err}, keeping the current config");
+            return;
+        }
+    };
+
+    if config.hosts_changed(&service.config) {
+        info!("MPD hosts changed, reconnecting");
+        *mpd = HostPool::new(
+            config.hosts.clone(),
+            config.host_selection,
+            Duration::from_secs(IDLE_TIME),
+        );
+
+        // the new pool starts out with no connections,
+        // which would otherwise leave nothing to re-render from
+        if !mpd.wait_connected().await {
+            warn!("Could not connect to any of the new MPD hosts yet");
+        }
+    }
+
+    if config.mpd_offline.check_interval != service.config.mpd_offline.check_interval {
+        *watchdog = create_watchdog(config.mpd_offline.check_interval);
+    }
+
+    service.reload(config);
+
+    if mpd_online {
+        refresh(mpd, service).await;
+    } else {
+        service.set_offline();
+    }
+}
+
+/// Plays the track from a friend's join secret on the active MPD host.
+async fn join(mpd: &HostPool, secret: &str) {
+    let secret = match JoinSecret::decode(secret) {
+        Ok(secret) => secret,
+        Err(err) => {
+            error!("{err}");
+            return;
+        }
+    };
+
+    let Some((_, client)) = mpd.active().await else {
+        error!("Failed to join {secret}, MPD is not connected");
+        return;
+    };
+
+    if let Err(err) = join::join(&client, &secret).await {
+        warn!("{err}");
+    }
+}
+
+/// Queries the active MPD host for the current status and song,
+/// and updates the activity to match.
+async fn refresh(mpd: &HostPool, service: &mut Service) {
+    let Some((host, client)) = mpd.active().await else {
+        return;
+    };
+
+    let Ok(status) = client.command(commands::Status).await else {
+        return;
+    };
+
+    let current_song = client.command(commands::CurrentSong).await.ok().flatten();
+
+    service.set_host(host);
+    service.update_state(&status, current_song).await;
+}
+
+/// Creates a Discord client for an application,
+/// with callbacks which forward its events to the main loop.
+fn create_client(id: u64, event_tx: &EventSender) -> DiscordClient {
+    let event_tx = event_tx.clone();
+    let event_tx2 = event_tx.clone();
+    let event_tx3 = event_tx.clone();
+    let event_tx4 = event_tx.clone();
+    let event_tx5 = event_tx.clone();
+
+    let drpc = DiscordClient::with_error_config(id, Duration::from_secs(IDLE_TIME), Some(0));
+
+    drpc.on_ready(move |_| {
+        info!("discord rpc ready");
+        event_tx.send(ServiceEvent::Ready);
+    })
+    .persist();
+
+    drpc.on_connected(move |_| {
+        info!("discord rpc connected");
+        event_tx2.send(ServiceEvent::Ready);
+    })
+    .persist();
+
+    drpc.on_disconnected(move |_| {
+        info!("discord rpc disconnected");
+
+        event_tx3.send(ServiceEvent::Error(
+            ErrorKind::Transient,
+            "disconnected".to_string(),
+        ));
+    })
+    .persist();
+
+    drpc.on_error(move |err| {
+        if let EventData::Error(err) = err.event {
+            let msg = err.message.clone().unwrap_or_default();
+            if let Some(kind) = reconnect::classify(&msg) {
+                debug!("{err:?}");
+                event_tx4.send(ServiceEvent::Error(kind, msg));
+            } else {
+                error!("{err:?}");
+            }
+        }
+    })
+    .persist();
+
+    drpc.on_activity_join(move |ctx| {
+        if let EventData::ActivityJoin(event) = ctx.event {
+            if let Some(secret) = event.secret.clone() {
+                info!("Joining friend's listening session");
+                event_tx5.send(ServiceEvent::Join(secret));
+            } else {
+                warn!("Discord sent a join request without a secret, ignoring it");
+            }
+        }
+    })
+    .persist();
+
+    drpc
+}
+
+/// Creates the album art client, which only uses cached art
+/// in all but one of the copies started for `discord_socket = "all"`.
+fn create_album_art_client(config: &AlbumArtConfig) -> AlbumArtClient {
+    let client = AlbumArtClient::new(config);
+
+    if discord_socket::is_cache_only() {
+        client.cache_only()
+    } else {
+        client
+    }
+}
+
+struct Service {
+    config: Config,
     album_art_client: AlbumArtClient,
     drpc: DiscordClient,
     tokens: Tokens,
+    last_activity: Option<Activity>,
+    enabled: bool,
+    /// Identifies this session's party to friends joining it
+    party_id: String,
+    event_tx: EventSender,
+    /// The Discord application the client is connected as
+    app_id: u64,
+    /// Index of the MPD host the status is being shown for
+    host: Option<usize>,
+    /// Whether Discord rejected the current application,
+    /// so the client should not be reconnected
+    rejected: bool,
 }
 
-impl<'a> Service<'a> {
-    fn new(config: &'a Config, tokens: Tokens, event_tx: mpsc::Sender<ServiceEvent>) -> Self {
-        let event_tx2 = event_tx.clone();
-        let event_tx3 = event_tx.clone();
-        let event_tx4 = event_tx.clone();
-
-        let drpc =
-            DiscordClient::with_error_config(config.id, Duration::from_secs(IDLE_TIME), Some(0));
-
-        drpc.on_ready(move |_| {
-            info!("discord rpc ready");
-            event_tx
-                .try_send(ServiceEvent::Ready)
-                .expect("channel to be open");
-        })
-        .persist();
-
-        drpc.on_connected(move |_| {
-            info!("discord rpc connected");
-            event_tx2
-                .try_send(ServiceEvent::Ready)
-                .expect("channel to be open");
-        })
-        .persist();
-
-        drpc.on_disconnected(move |_| {
-            info!("discord rpc disconnected");
-
-            event_tx3
-                .try_send(ServiceEvent::Error("disconnected".to_string()))
-                .expect("channel to be open");
-        })
-        .persist();
-
-        drpc.on_error(move |err| {
-            if let EventData::Error(err) = err.event {
-                error!("{err:?}");
-                let msg = err.message.unwrap_or_default();
-                if msg.to_lowercase().starts_with("io err") {
-                    event_tx4
-                        .try_send(ServiceEvent::Error(msg))
-                        .expect("channel to be open");
-                }
-            }
-        })
-        .persist();
+impl Service {
+    fn new(config: Config, event_tx: EventSender) -> Self {
+        let drpc = create_client(config.id, &event_tx);
+        let app_id = config.id;
+
+        let album_art_client = create_album_art_client(&config.album_art);
+        let tokens = Tokens::new(&config.format);
 
-        let album_art_client = AlbumArtClient::new();
         Self {
             config,
             album_art_client,
             drpc,
             tokens,
+            last_activity: None,
+            enabled: true,
+            party_id: format!("mpd-discord-rpc-{}", std::process::id()),
+            event_tx,
+            app_id,
+            host: None,
+            rejected: false,
         }
     }
 
@@ -198,120 +584,321 @@ impl<'a> Service<'a> {
         self.drpc.start();
     }
 
-    async fn update_state(&mut self, status: &Status, current_song: Option<SongInQueue>) {
-        // https://discord.com/developers/docs/rich-presence/how-to#updating-presence-update-presence-payload
-        const MAX_BYTES: usize = 128;
+    /// Swaps in a newly loaded config,
+    /// switching Discord application only if its ID has changed.
+    fn reload(&mut self, config: Config) {
+        if config.discord_socket != self.config.discord_socket {
+            warn!("Changes to discord_socket only take effect after a restart");
+        }
+
+        // host indexes may now refer to different hosts
+        if config.hosts_changed(&self.config) {
+            self.host = None;
+        }
+
+        if config.album_art != self.config.album_art {
+            self.album_art_client = create_album_art_client(&config.album_art);
+        }
+
+        self.config = config;
+        self.tokens = Tokens::new(self.config.format_for(self.host));
+        self.set_app_id(self.config.id_for(self.host));
+    }
+
+    /// Switches to the format and Discord application of the active MPD host,
+    /// if it has changed since the last update.
+    fn set_host(&mut self, host: usize) {
+        if self.host == Some(host) {
+            return;
+        }
+
+        if let Some(config) = self.config.hosts.get(host) {
+            info!("Showing status of MPD host '{}'", config.name);
+        }
+
+        self.host = Some(host);
+        self.tokens = Tokens::new(self.config.format_for(self.host));
+        self.set_app_id(self.config.id_for(self.host));
+    }
+
+    /// Reconnects to Discord as another application,
+    /// if it differs from the current one.
+    fn set_app_id(&mut self, id: u64) {
+        if id == self.app_id {
+            return;
+        }
 
-        let format = &self.config.format;
+        info!("Switching to Discord application {id}");
+        self.clear_activity();
 
+        let drpc = std::mem::replace(&mut self.drpc, create_client(id, &self.event_tx));
+        if let Err(why) = drpc.shutdown() {
+            debug!("Failed to shut down previous Discord client: {why:?}");
+        }
+
+        self.app_id = id;
+        self.rejected = false;
+        self.start();
+    }
+
+    /// Turns the presence on or off.
+    /// Returns whether it is now enabled.
+    fn toggle(&mut self) -> bool {
+        self.enabled = !self.enabled;
+
+        if self.enabled {
+            info!("Presence enabled");
+        } else {
+            info!("Presence disabled");
+            self.clear_activity();
+        }
+
+        self.enabled
+    }
+
+    /// Clears the presence and flushes the caches to disk before exiting.
+    fn shutdown(&mut self) {
+        self.clear_activity();
+        self.album_art_client.save_cache();
+    }
+
+    /// Asks Discord to send join events, which it only does to subscribed clients.
+    /// Needed again after every reconnect, including when switching application.
+    fn subscribe_join(&mut self) {
+        if let Err(why) = self.drpc.subscribe(Event::ActivityJoin, |args| args) {
+            warn!("Failed to subscribe to join requests: {why:?}");
+        }
+    }
+
+    fn clear_activity(&mut self) {
+        if let Err(why) = self.drpc.clear_activity() {
+            error!("Failed to clear activity: {why:?}");
+        }
+    }
+
+    async fn update_state(&mut self, status: &Status, current_song: Option<SongInQueue>) {
         if matches!(status.state, PlayState::Playing) {
             if let Some(song_in_queue) = current_song {
-                let song = song_in_queue.song;
-
-                let mut details = clamp(
-                    replace_tokens(&format.details, &self.tokens.details, &song, status),
-                    MAX_BYTES,
-                );
-                let state = clamp(
-                    replace_tokens(&format.state, &self.tokens.state, &song, status),
-                    MAX_BYTES,
-                );
-                let large_text =
-                    replace_tokens(&format.large_text, &self.tokens.large_text, &song, status);
-                let small_text =
-                    replace_tokens(&format.small_text, &self.tokens.small_text, &song, status);
-
-                let button1_text = replace_tokens(
-                    &format.button1_text,
-                    &self.tokens.button1_text,
-                    &song,
-                    status,
-                );
-                let button1_link = replace_tokens(
-                    &format.button1_link,
-                    &self.tokens.button1_link,
-                    &song,
-                    status,
-                );
-                let button2_text = replace_tokens(
-                    &format.button2_text,
-                    &self.tokens.button2_text,
-                    &song,
-                    status,
-                );
-                let button2_link = replace_tokens(
-                    &format.button2_link,
-                    &self.tokens.button2_link,
-                    &song,
-                    status,
-                );
-
-                // discord requires details to be at least two characters. So extend it with
-                // zero-width spaces if it's too short. https://en.wikipedia.org/wiki/Zero-width_space
-                while details.chars().count() < 2 {
-                    details.push('\u{200B}');
-                }
+                let activity = self.render_activity(status, song_in_queue.song).await;
+                self.set_activity(&activity);
+                self.last_activity = Some(activity);
+            }
+        } else {
+            self.last_activity = None;
+
+            if self.enabled {
+                self.clear_activity();
+            }
+        }
+    }
 
-                let timestamps = get_timestamp(status, format.timestamp);
-
-                let url = self.album_art_client.get_album_art_url(song).await;
-
-                let display_type = map_display_type(format.display_type);
-
-                let res = self.drpc.set_activity(|act| {
-                    let mut act = act
-                        .state(state)
-                        .activity_type(ActivityType::Listening)
-                        .details(details)
-                        .status_display(display_type)
-                        .assets(|mut assets| {
-                            match url {
-                                Some(url) => assets = assets.large_image(url),
-                                None => {
-                                    if !format.large_image.is_empty() {
-                                        assets = assets.large_image(&format.large_image);
-                                    }
-                                }
-                            }
-
-                            if !format.small_image.is_empty() {
-                                assets = assets.small_image(&format.small_image);
-                            }
-                            if !large_text.is_empty() {
-                                assets = assets.large_text(large_text);
-                            }
-                            if !small_text.is_empty() {
-                                assets = assets.small_text(small_text);
-                            }
-                            assets
-                        })
-                        .timestamps(|_| timestamps);
-
-                    // add buttons. This should suffice since only 2 are supported by Discord
-                    if !button1_text.is_empty() && !button1_link.is_empty() {
-                        act = act.append_buttons(|_| {
-                            ActivityButton::new().label(button1_text).url(button1_link)
-                        });
+    /// Renders the activity for the playing song with the current host's format,
+    /// resolving its album art.
+    async fn render_activity(&mut self, status: &Status, song: Song) -> Activity {
+        let format = self.config.format_for(self.host);
+        let host = self
+            .host
+            .and_then(|host| self.config.hosts.get(host))
+            .map_or("", |host| host.name.as_str());
+
+        let render = |format_string: &str, tokens: &Vec<String>, max_bytes: usize| {
+            render_text(
+                format_string,
+                tokens,
+                &song,
+                status,
+                host,
+                format,
+                max_bytes,
+            )
+        };
+        let link = |link: &str, tokens: &Vec<String>| {
+            render_link(link, tokens, &song, status, host, &format.listenbrainz_user)
+        };
+
+        let details = render(&format.details, &self.tokens.details, TEXT_MAX_BYTES);
+        let state = render(&format.state, &self.tokens.state, TEXT_MAX_BYTES);
+        let large_text = render(&format.large_text, &self.tokens.large_text, TEXT_MAX_BYTES);
+        let small_text = render(&format.small_text, &self.tokens.small_text, TEXT_MAX_BYTES);
+
+        let button1_text = render(
+            &format.button1_text,
+            &self.tokens.button1_text,
+            BUTTON_LABEL_MAX_BYTES,
+        );
+        let button1_link = link(&format.button1_link, &self.tokens.button1_link);
+        let button2_text = render(
+            &format.button2_text,
+            &self.tokens.button2_text,
+            BUTTON_LABEL_MAX_BYTES,
+        );
+        let button2_link = link(&format.button2_link, &self.tokens.button2_link);
+
+        let timestamps = get_timestamp(status, format.timestamp);
+        let party_size = get_party_size(&song, status, format.party_size);
+
+        let join_secret = if format.allow_join {
+            JoinSecret::new(&song, status).and_then(JoinSecret::encode)
+        } else {
+            None
+        };
+
+        let (activity_type, display_type) = self.tokens.get_types(format, &song, status, host);
+
+        let small_image = if format.small_image == ARTIST_IMAGE_TOKEN {
+            self.album_art_client
+                .get_artist_image_url(&song)
+                .await
+                .unwrap_or_else(default_image)
+        } else {
+            format.small_image.clone()
+        };
+
+        let large_image = if format.album_art {
+            self.album_art_client
+                .get_album_art_url(song)
+                .await
+                .unwrap_or_else(|| format.large_image.clone())
+        } else {
+            format.large_image.clone()
+        };
+
+        // add buttons. This should suffice since only 2 are supported by Discord
+        let buttons = [(button1_text, button1_link), (button2_text, button2_link)]
+            .into_iter()
+            .filter(|(text, link)| !text.is_empty() && !link.is_empty())
+            .collect();
+
+        let mut activity = Activity {
+            details,
+            state,
+            large_image,
+            small_image,
+            large_text,
+            small_text,
+            activity_type,
+            display_type,
+            buttons,
+            party_size,
+            join_secret,
+            timestamps,
+            rendered_at: Instant::now(),
+        };
+
+        activity.fit_limits(&format.ellipsis);
+        activity
+    }
+
+    /// Renders the activity for a song playing on a host,
+    /// without switching Discord application or sending it.
+    async fn preview(&mut self, host: usize, status: &Status, song: Song) -> Activity {
+        self.host = Some(host);
+        self.tokens = Tokens::new(self.config.format_for(self.host));
+
+        self.render_activity(status, song).await
+    }
+
+    /// Replaces the activity with the configured "MPD offline" status,
+    /// or clears it if none is configured.
+    fn set_offline(&mut self) {
+        self.last_activity = None;
+
+        let offline = &self.config.mpd_offline;
+        let format = self.config.format_for(self.host);
+
+        if offline.details.is_empty() {
+            self.clear_activity();
+            return;
+        }
+
+        let mut activity = Activity {
+            details: offline.details.clone(),
+            state: offline.state.clone(),
+            large_image: offline.large_image.clone(),
+            small_image: String::new(),
+            large_text: String::new(),
+            small_text: String::new(),
+            activity_type: format.activity_type,
+            display_type: format.display_type,
+            buttons: vec![],
+            party_size: None,
+            join_secret: None,
+            timestamps: Timestamps::default(),
+            rendered_at: Instant::now(),
+        };
+
+        activity.fit_limits(&format.ellipsis);
+        self.set_activity(&activity);
+    }
+
+    /// Sends the last rendered activity again,
+    /// as long as the song it was rendered for should still be playing.
+    ///
+    /// Returns `false` if there is nothing fresh to send,
+    /// in which case the state should be queried from MPD instead.
+    fn replay(&mut self) -> bool {
+        match self.last_activity.clone() {
+            Some(activity) if !activity.is_stale() => {
+                debug!("Replaying last activity");
+                self.set_activity(&activity);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn set_activity(&mut self, activity: &Activity) {
+        if !self.enabled {
+            return;
+        }
+
+        let res = self.drpc.set_activity(|act| {
+            let mut act = act
+                .state(&activity.state)
+                .activity_type(map_activity_type(activity.activity_type))
+                .details(&activity.details)
+                .status_display(map_display_type(activity.display_type))
+                .assets(|mut assets| {
+                    if !activity.large_image.is_empty() {
+                        assets = assets.large_image(&activity.large_image);
                     }
-                    if !button2_text.is_empty() && !button2_link.is_empty() {
-                        act = act.append_buttons(|_| {
-                            ActivityButton::new().label(button2_text).url(button2_link)
-                        });
+                    if !activity.small_image.is_empty() {
+                        assets = assets.small_image(&activity.small_image);
                     }
-                    act
-                });
-
-                if let Err(why) = res {
-                    // api returns a bogus error about missing buttons but succeeds anyway
-                    // so don't log it
-                    if !matches!(&why, DiscordError::JsonError(err) if err.to_string().starts_with("missing field `buttons`"))
-                    {
-                        error!("Failed to set activity: {why:?}");
+                    if !activity.large_text.is_empty() {
+                        assets = assets.large_text(&activity.large_text);
                     }
-                }
+                    if !activity.small_text.is_empty() {
+                        assets = assets.small_text(&activity.small_text);
+                    }
+                    assets
+                })
+                .timestamps(|_| activity.timestamps.to_activity_timestamps());
+
+            if let Some(secret) = &activity.join_secret {
+                // discord only shows the join button for a party with space left
+                let size = activity.party_size.unwrap_or((1, 2));
+
+                act = act
+                    .party(|party| party.id(&self.party_id).size(size))
+                    .secrets(|secrets| secrets.join(secret));
+            } else if let Some(size) = activity.party_size {
+                act = act.party(|party| party.size(size));
+            }
+
+            for (text, link) in &activity.buttons {
+                act = act.append_buttons(|_| ActivityButton::new().label(text).url(link));
+            }
+            act
+        });
+
+        if let Err(why) = res {
+            // api returns a bogus error about missing buttons but succeeds anyway
+            // so don't log it
+            if !matches!(&why, DiscordError::JsonError(err) if err.to_string().starts_with("missing field `buttons`"))
+            {
+                error!("Failed to set activity: {why:?}");
             }
-        } else if let Err(why) = self.drpc.clear_activity() {
-            error!("Failed to clear activity: {why:?}");
         }
     }
 }
@@ -327,33 +914,93 @@ fn get_tokens(re: &Regex, format_string: &str) -> Vec<String> {
 /// with actual data pulled from MPD
 fn replace_tokens(
     format_string: &str,
-    tokens: &Vec<String>,
+    tokens: &[String],
     song: &Song,
     status: &Status,
+    host: &str,
 ) -> String {
-    let mut compiled_string = format_string.to_string();
-    for token in tokens {
-        let value = mpd_conn::get_token_value(song, status, token);
-        compiled_string = compiled_string.replace(format!("${token}").as_str(), value.as_str());
-    }
-    compiled_string
+    substitute(format_string, &get_token_values(tokens, song, status, host))
 }
 
-/// Clamps a string to a specified length (byte count).
+/// Replaces the formatting tokens like [`replace_tokens`],
+/// shortening their values if the result is longer than the max length (byte count).
 ///
-/// If a string is longer than the max length,
-/// it is cut down and ellipses are added
-/// to make the byte count equal to or just below the max.
-fn clamp(mut str: String, len: usize) -> String {
-    const ELLIPSES_LEN: usize = 3;
+/// Values are shortened in the order set in the format config,
+/// and literal text is never changed.
+fn render_text(
+    format_string: &str,
+    tokens: &[String],
+    song: &Song,
+    status: &Status,
+    host: &str,
+    format: &Format,
+    max_bytes: usize,
+) -> String {
+    let mut values = get_token_values(tokens, song, status, host);
+    let rendered = substitute(format_string, &values);
+
+    if rendered.len() <= max_bytes {
+        return rendered;
+    }
+
+    limits::shorten_tokens(
+        &mut values,
+        rendered.len(),
+        max_bytes,
+        &format.ellipsis,
+        &format.truncate_order,
+    );
+
+    substitute(format_string, &values)
+}
+
+/// Gets the value of each distinct formatting token from MPD
+fn get_token_values(
+    tokens: &[String],
+    song: &Song,
+    status: &Status,
+    host: &str,
+) -> Vec<TokenValue> {
+    let mut values: Vec<TokenValue> = vec![];
 
-    if str.len() > len {
-        while str.len() > (len - ELLIPSES_LEN) {
-            str.pop();
+    for token in tokens {
+        match values.iter_mut().find(|value| &value.token == token) {
+            Some(value) => value.count += 1,
+            None => values.push(TokenValue {
+                token: token.clone(),
+                value: mpd_conn::get_token_value(song, status, host, token),
+                count: 1,
+            }),
         }
+    }
+
+    values
+}
 
-        str.push_str("...");
+/// Replaces each of the formatting tokens in the formatting string with its value
+fn substitute(format_string: &str, values: &[TokenValue]) -> String {
+    let mut compiled_string = format_string.to_string();
+    for TokenValue { token, value, .. } in values {
+        compiled_string = compiled_string.replace(format!("${token}").as_str(), value.as_str());
     }
+    compiled_string
+}
 
-    str
+/// Builds a button link, either from a preset such as `@youtube_search`
+/// or by replacing the formatting tokens in it.
+///
+/// Returns an empty string if a preset cannot be resolved for the song,
+/// which hides the button.
+fn render_link(
+    link: &str,
+    tokens: &[String],
+    song: &Song,
+    status: &Status,
+    host: &str,
+    listenbrainz_user: &str,
+) -> String {
+    match link.strip_prefix('@') {
+        Some(preset) => links::get_preset_url(preset, song, listenbrainz_user).unwrap_or_default(),
+        None => replace_tokens(link, tokens, song, status, host),
+    }
 }
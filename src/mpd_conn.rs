@@ -1,8 +1,13 @@
-use crate::config::TimestampMode;
+use crate::config::{Host, PartySizeMode, TimestampMode};
 use discord_presence::models::ActivityTimestamps;
+use mpd_client::Client;
+use mpd_client::client::{ConnectWithPasswordError, ConnectionEvents};
 use mpd_client::responses::{Song, Status};
 use mpd_client::tag::Tag;
 use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tracing::error;
 
 /// Formats a duration given in seconds
 /// in hh:mm format
@@ -15,7 +20,9 @@ fn format_time(time: u64) -> String {
 
 /// Converts a string format token value
 /// into its respective MPD value.
-pub fn get_token_value(song: &Song, status: &Status, token: &str) -> String {
+///
+/// `host` is the display name of the MPD host the song is playing on.
+pub fn get_token_value(song: &Song, status: &Status, host: &str, token: &str) -> String {
     match token {
         "title" => song.title(),
         "album" => try_get_first_tag(song.tags.get(&Tag::Album)),
@@ -28,45 +35,106 @@ pub fn get_token_value(song: &Song, status: &Status, token: &str) -> String {
         "originaldate" => try_get_first_tag(song.tags.get(&Tag::OriginalDate)),
         "duration" => return get_duration(status).map_or_else(|| String::from("N/A"), format_time),
         "elapsed" => return get_elapsed(status).map_or_else(|| String::from("N/A"), format_time),
+        "host" => Some(host),
         _ => Some(token),
     }
     .unwrap_or("unknown")
     .to_string()
 }
 
-/// Gets the activity timestamp based off the current song elapsed/remaining
-pub fn get_timestamp(status: &Status, mode: TimestampMode) -> ActivityTimestamps {
-    let current_time = SystemTime::now()
+/// Wall-clock anchors for the activity timestamps.
+///
+/// These stay correct however long after rendering they are sent,
+/// as long as the song keeps playing.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timestamps {
+    pub start: Option<u64>,
+    pub end: Option<u64>,
+    /// When the current song finishes, regardless of the timestamp mode
+    pub song_end: Option<u64>,
+}
+
+impl Timestamps {
+    pub fn to_activity_timestamps(self) -> ActivityTimestamps {
+        let mut timestamps = ActivityTimestamps::new();
+
+        if let Some(start) = self.start {
+            timestamps = timestamps.start(start);
+        }
+        if let Some(end) = self.end {
+            timestamps = timestamps.end(end);
+        }
+
+        timestamps
+    }
+}
+
+/// Gets the current time as a unix timestamp
+pub fn now() -> u64 {
+    SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .expect("Failed to get system time")
-        .as_secs();
-
-    let timestamps = ActivityTimestamps::new();
+        .as_secs()
+}
 
+/// Gets the activity timestamp based off the current song elapsed/remaining
+pub fn get_timestamp(status: &Status, mode: TimestampMode) -> Timestamps {
     let Some(elapsed) = get_elapsed(status) else {
-        return timestamps;
+        return Timestamps::default();
     };
 
-    match mode {
-        TimestampMode::Left => {
-            let Some(duration) = get_duration(status) else {
-                return timestamps;
-            };
+    let start = now() - elapsed;
+    let song_end = get_duration(status).map(|duration| start + duration);
+
+    let (start, end) = match mode {
+        TimestampMode::Left => (None, song_end),
+        TimestampMode::Off => (None, None),
+        TimestampMode::Elapsed => (Some(start), None),
+        TimestampMode::Both => match song_end {
+            Some(end) => (Some(start), Some(end)),
+            None => (None, None),
+        },
+    };
 
-            let remaining = duration - elapsed;
-            timestamps.end(current_time + remaining)
+    Timestamps {
+        start,
+        end,
+        song_end,
+    }
+}
+
+/// Gets the current position and total for the party size.
+///
+/// In queue mode, this is the song's position in the queue.
+/// In album mode, this is read from the track tag,
+/// which must be in the form `3/12` or have a separate `TRACKTOTAL` tag.
+pub fn get_party_size(song: &Song, status: &Status, mode: PartySizeMode) -> Option<(u32, u32)> {
+    let (current, total) = match mode {
+        PartySizeMode::Off => return None,
+        PartySizeMode::Queue => {
+            let (position, _) = status.current_song.as_ref()?;
+            (position.0 + 1, status.playlist_length)
         }
-        TimestampMode::Off => timestamps,
-        TimestampMode::Elapsed => timestamps.start(current_time - elapsed),
-        TimestampMode::Both => {
-            let Some(duration) = get_duration(status) else {
-                return timestamps;
+        PartySizeMode::Album => {
+            let track = try_get_first_tag(song.tags.get(&Tag::Track))?;
+
+            let (current, total) = match track.split_once('/') {
+                Some((current, total)) => (current, total),
+                None => (
+                    track,
+                    try_get_first_tag(song.tags.get(&Tag::Other("TRACKTOTAL".into())))?,
+                ),
             };
-            let start_timestamp = current_time - elapsed;
-            let end_timestamp = start_timestamp + duration;
-            timestamps.start(start_timestamp).end(end_timestamp)
+
+            (current.trim().parse().ok()?, total.trim().parse().ok()?)
         }
+    };
+
+    if current == 0 || current > total {
+        return None;
     }
+
+    Some((u32::try_from(current).ok()?, u32::try_from(total).ok()?))
 }
 
 /// Attempts to read the first value for a tag
@@ -84,3 +152,241 @@ fn get_duration(status: &Status) -> Option<u64> {
 fn get_elapsed(status: &Status) -> Option<u64> {
     status.elapsed.map(|e| e.as_secs())
 }
+
+/// Default port MPD listens on
+const DEFAULT_PORT: u16 = 6600;
+
+/// Where an MPD host can be reached.
+#[derive(Debug, PartialEq, Eq)]
+enum Endpoint<'a> {
+    /// A `host:port` address, or a hostname using the default port
+    Tcp(&'a str),
+    /// A Unix socket path
+    Unix(&'a str),
+    /// A Linux abstract socket name, given as `@name`
+    Abstract(&'a str),
+}
+
+/// Splits an address in the forms MPD clients accept
+/// into its password, if it has one, and where to connect.
+///
+/// The password comes before the first `@`, like `password@host:port`,
+/// unless the address starts with `@`, in which case it is an abstract socket.
+fn parse_address(address: &str) -> (Option<&str>, Endpoint<'_>) {
+    let (password, address) = match address.split_once('@') {
+        Some((password, address)) if !password.is_empty() => (Some(password), address),
+        _ => (None, address),
+    };
+
+    let endpoint = if let Some(name) = address.strip_prefix('@') {
+        Endpoint::Abstract(name)
+    } else if address.starts_with('/') {
+        Endpoint::Unix(address)
+    } else {
+        Endpoint::Tcp(address)
+    };
+
+    (password, endpoint)
+}
+
+/// Checks whether a TCP address includes a port.
+///
+/// IPv6 addresses can only be given a port inside brackets, like `[::1]:6600`,
+/// so any other address with more than one colon has none.
+fn has_port(address: &str) -> bool {
+    match address.rsplit_once(']') {
+        Some((_, rest)) => rest.starts_with(':'),
+        None => address.matches(':').count() == 1,
+    }
+}
+
+/// Why connecting to an MPD host failed.
+#[derive(Debug)]
+pub enum OpenError {
+    /// MPD rejected the password
+    IncorrectPassword,
+    Other(String),
+}
+
+impl std::fmt::Display for OpenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IncorrectPassword => write!(f, "incorrect password"),
+            Self::Other(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl From<std::io::Error> for OpenError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Other(err.to_string())
+    }
+}
+
+/// Gets the password for a host.
+///
+/// The `password` option is used first,
+/// then a password in the address, then the `password_file` option.
+fn get_password(host: &Host, from_address: Option<&str>) -> Result<Option<String>, OpenError> {
+    if let Some(password) = host.password.as_deref().or(from_address) {
+        return Ok(Some(password.to_string()));
+    }
+
+    host.password_file
+        .as_ref()
+        .map(|path| {
+            std::fs::read_to_string(path)
+                .map(|password| password.trim().to_string())
+                .map_err(|err| {
+                    OpenError::Other(format!(
+                        "Failed to read password file '{}': {err}",
+                        path.display()
+                    ))
+                })
+        })
+        .transpose()
+}
+
+/// Opens a connection to an MPD host,
+/// sending its password if it has one.
+pub async fn open(host: &Host) -> Result<(Client, ConnectionEvents), OpenError> {
+    let (password, endpoint) = parse_address(&host.address);
+    let password = get_password(host, password)?;
+    let password = password.as_deref();
+
+    match endpoint {
+        Endpoint::Tcp(address) if has_port(address) => {
+            handshake(TcpStream::connect(address).await?, password).await
+        }
+        Endpoint::Tcp(address) => {
+            let host = address.trim_start_matches('[').trim_end_matches(']');
+            handshake(TcpStream::connect((host, DEFAULT_PORT)).await?, password).await
+        }
+        #[cfg(unix)]
+        Endpoint::Unix(path) => {
+            handshake(tokio::net::UnixStream::connect(path).await?, password).await
+        }
+        #[cfg(target_os = "linux")]
+        Endpoint::Abstract(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            use std::os::unix::net::{SocketAddr, UnixStream};
+
+            let addr = SocketAddr::from_abstract_name(name.as_bytes())?;
+            let stream = UnixStream::connect_addr(&addr)?;
+            stream.set_nonblocking(true)?;
+
+            handshake(tokio::net::UnixStream::from_std(stream)?, password).await
+        }
+        #[allow(unreachable_patterns)]
+        endpoint => Err(OpenError::Other(format!(
+            "{endpoint:?} is not supported on this platform"
+        ))),
+    }
+}
+
+async fn handshake<C>(
+    connection: C,
+    password: Option<&str>,
+) -> Result<(Client, ConnectionEvents), OpenError>
+where
+    C: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    Client::connect_with_password_opt(connection, password)
+        .await
+        .map_err(|err| match err {
+            ConnectWithPasswordError::IncorrectPassword => OpenError::IncorrectPassword,
+            err => OpenError::Other(format!("{err:?}")),
+        })
+}
+
+/// Opens a standalone connection to a single MPD host,
+/// for one-off commands outside of the main loop.
+pub async fn connect(host: &Host) -> Option<Client> {
+    match open(host).await {
+        Ok((client, _events)) => Some(client),
+        Err(err) => {
+            error!("Failed to connect to MPD host '{}': {err}", host.name);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_address_tcp() {
+        assert_eq!(
+            parse_address("localhost:6600"),
+            (None, Endpoint::Tcp("localhost:6600"))
+        );
+        assert_eq!(
+            parse_address("localhost"),
+            (None, Endpoint::Tcp("localhost"))
+        );
+        assert_eq!(
+            parse_address("[::1]:6600"),
+            (None, Endpoint::Tcp("[::1]:6600"))
+        );
+    }
+
+    #[test]
+    fn has_port_handles_ipv6() {
+        assert!(has_port("localhost:6600"));
+        assert!(has_port("[::1]:6600"));
+        assert!(!has_port("localhost"));
+        assert!(!has_port("[::1]"));
+        assert!(!has_port("::1"));
+        assert!(!has_port("fe80::1:6600"));
+    }
+
+    #[test]
+    fn parse_address_sockets() {
+        assert_eq!(
+            parse_address("/run/mpd/socket"),
+            (None, Endpoint::Unix("/run/mpd/socket"))
+        );
+        assert_eq!(parse_address("@mpd"), (None, Endpoint::Abstract("mpd")));
+    }
+
+    #[test]
+    fn parse_address_passwords() {
+        assert_eq!(
+            parse_address("secret@localhost:6600"),
+            (Some("secret"), Endpoint::Tcp("localhost:6600"))
+        );
+        assert_eq!(
+            parse_address("secret@/run/mpd/socket"),
+            (Some("secret"), Endpoint::Unix("/run/mpd/socket"))
+        );
+        assert_eq!(
+            parse_address("secret@@mpd"),
+            (Some("secret"), Endpoint::Abstract("mpd"))
+        );
+    }
+
+    #[test]
+    fn get_password_prefers_option_over_address() {
+        let mut host = Host::from("localhost".to_string());
+        assert_eq!(get_password(&host, None).unwrap(), None);
+        assert_eq!(
+            get_password(&host, Some("address")).unwrap().as_deref(),
+            Some("address")
+        );
+
+        host.password = Some("option".to_string());
+        assert_eq!(
+            get_password(&host, Some("address")).unwrap().as_deref(),
+            Some("option")
+        );
+    }
+
+    #[test]
+    fn get_password_reports_missing_file() {
+        let mut host = Host::from("localhost".to_string());
+        host.password_file = Some("/nonexistent/mpd-password".into());
+
+        assert!(get_password(&host, None).is_err());
+    }
+}
@@ -0,0 +1,94 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+/// Upper bound on the delay between reconnect attempts.
+const MAX_DELAY: Duration = Duration::from_secs(300);
+
+/// How a Discord error should be handled.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The IPC socket could not be found or connected to,
+    /// which almost always means Discord is closed.
+    NotRunning,
+    /// The connection dropped or failed part-way, and is worth retrying.
+    Transient,
+    /// Discord rejected the client, so retrying will not help.
+    Fatal,
+}
+
+/// Classifies an error message reported by the Discord client.
+///
+/// Returns `None` for errors which do not affect the connection,
+/// such as Discord rejecting an activity payload.
+pub fn classify(message: &str) -> Option<ErrorKind> {
+    const NOT_RUNNING: &[&str] = &[
+        "no such file",
+        "connection refused",
+        "not found",
+        "couldn't connect",
+        "could not connect",
+    ];
+    const FATAL: &[&str] = &["invalid client id", "invalid client", "unauthorized"];
+
+    let message = message.to_lowercase();
+
+    if FATAL.iter().any(|pattern| message.contains(pattern)) {
+        Some(ErrorKind::Fatal)
+    } else if message.starts_with("io err") {
+        if NOT_RUNNING.iter().any(|pattern| message.contains(pattern)) {
+            Some(ErrorKind::NotRunning)
+        } else {
+            Some(ErrorKind::Transient)
+        }
+    } else if message == "disconnected" {
+        Some(ErrorKind::Transient)
+    } else {
+        None
+    }
+}
+
+/// Capped exponential backoff with jitter.
+#[derive(Debug)]
+pub struct Backoff {
+    base: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub const fn new(base: Duration) -> Self {
+        Self { base, attempt: 0 }
+    }
+
+    /// Gets the delay before the next attempt,
+    /// doubling it each time up to a limit.
+    ///
+    /// Up to a quarter of the delay is randomly added or removed,
+    /// so that clients do not retry in lockstep.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self
+            .base
+            .saturating_mul(2u32.saturating_pow(self.attempt))
+            .min(MAX_DELAY);
+        self.attempt = self.attempt.saturating_add(1);
+
+        let jitter_range = delay.as_millis() as u64 / 2;
+        if jitter_range == 0 {
+            return delay;
+        }
+
+        let jitter = random() % jitter_range;
+        (delay - Duration::from_millis(jitter_range / 2)) + Duration::from_millis(jitter)
+    }
+
+    /// Resets the delay after a successful connection.
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+/// Gets a random number without pulling in a dependency,
+/// by taking advantage of the randomly seeded std hasher.
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
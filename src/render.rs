@@ -0,0 +1,95 @@
+use mpd_client::commands;
+use mpd_client::responses::PlayState;
+
+use crate::activity::Activity;
+use crate::config::Config;
+use crate::{Service, events, mpd_conn};
+
+/// Renders the status for the song playing on MPD and prints it,
+/// without connecting to Discord.
+///
+/// The first playing host is used, or the first reachable one if none are playing.
+/// Returns an error message if no host could be reached.
+pub async fn run(config: Config) -> Result<(), String> {
+    let mut selected = None;
+
+    for (index, host) in config.hosts.iter().enumerate() {
+        let Some(client) = mpd_conn::connect(host).await else {
+            continue;
+        };
+
+        let Ok(status) = client.command(commands::Status).await else {
+            continue;
+        };
+
+        let playing = matches!(status.state, PlayState::Playing);
+        if playing || selected.is_none() {
+            selected = Some((index, client, status));
+        }
+
+        if playing {
+            break;
+        }
+    }
+
+    let (index, client, status) =
+        selected.ok_or_else(|| "Could not connect to any MPD host".to_string())?;
+    let name = config.hosts[index].name.clone();
+
+    let song = client
+        .command(commands::CurrentSong)
+        .await
+        .map_err(|err| format!("Failed to get the current song from '{name}': {err}"))?;
+
+    let Some(song) = song.filter(|_| matches!(status.state, PlayState::Playing)) else {
+        println!("Nothing is playing on MPD host '{name}', so no status would be shown");
+        return Ok(());
+    };
+
+    // the discord client is created but never started
+    let (tx, _rx) = events::channel();
+    let mut service = Service::new(config, tx);
+    let activity = service.preview(index, &status, song.song).await;
+
+    println!("{:<14}{name}", "host:");
+    print_activity(&activity);
+
+    Ok(())
+}
+
+fn print_activity(activity: &Activity) {
+    let field = |name: &str, value: &str| {
+        if !value.is_empty() {
+            println!("{:<14}{value}", format!("{name}:"));
+        }
+    };
+
+    field("details", &activity.details);
+    field("state", &activity.state);
+    field("large image", &activity.large_image);
+    field("large text", &activity.large_text);
+    field("small image", &activity.small_image);
+    field("small text", &activity.small_text);
+    field("type", &format!("{:?}", activity.activity_type));
+    field("display", &format!("{:?}", activity.display_type));
+
+    for (text, link) in &activity.buttons {
+        field("button", &format!("{text} -> {link}"));
+    }
+
+    if let Some((size, max)) = activity.party_size {
+        field("party size", &format!("{size} of {max}"));
+    }
+
+    if let Some(secret) = &activity.join_secret {
+        field("join secret", secret);
+    }
+
+    let timestamps = &activity.timestamps;
+    if let Some(start) = timestamps.start {
+        field("start", &start.to_string());
+    }
+    if let Some(end) = timestamps.end {
+        field("end", &end.to_string());
+    }
+}
@@ -0,0 +1,93 @@
+/// A process signal the main loop acts on.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Signal {
+    /// SIGTERM or SIGINT
+    Terminate,
+    /// SIGHUP
+    Reload,
+    /// SIGUSR1
+    Toggle,
+}
+
+impl Signal {
+    /// Sends the signal to another process.
+    #[cfg(unix)]
+    pub fn send_to(self, pid: u32) -> std::io::Result<()> {
+        let signal = match self {
+            Self::Terminate => libc::SIGTERM,
+            Self::Reload => libc::SIGHUP,
+            Self::Toggle => libc::SIGUSR1,
+        };
+
+        let pid = libc::pid_t::try_from(pid).map_err(std::io::Error::other)?;
+
+        // SAFETY: kill only takes plain integers, and has no memory safety requirements.
+        if unsafe { libc::kill(pid, signal) } == 0 {
+            Ok(())
+        } else {
+            Err(std::io::Error::last_os_error())
+        }
+    }
+
+    /// Sends the signal to another process.
+    #[cfg(not(unix))]
+    pub fn send_to(self, _pid: u32) -> std::io::Result<()> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "Sending signals is only supported on Unix",
+        ))
+    }
+}
+
+#[cfg(unix)]
+pub struct Signals {
+    terminate: tokio::signal::unix::Signal,
+    interrupt: tokio::signal::unix::Signal,
+    hangup: tokio::signal::unix::Signal,
+    user1: tokio::signal::unix::Signal,
+}
+
+#[cfg(unix)]
+impl Signals {
+    pub fn new() -> Self {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let listen = |kind: SignalKind| signal(kind).expect("Failed to listen for signal");
+
+        Self {
+            terminate: listen(SignalKind::terminate()),
+            interrupt: listen(SignalKind::interrupt()),
+            hangup: listen(SignalKind::hangup()),
+            user1: listen(SignalKind::user_defined1()),
+        }
+    }
+
+    /// Waits for the next signal.
+    pub async fn recv(&mut self) -> Signal {
+        tokio::select! {
+            _ = self.terminate.recv() => Signal::Terminate,
+            _ = self.interrupt.recv() => Signal::Terminate,
+            _ = self.hangup.recv() => Signal::Reload,
+            _ = self.user1.recv() => Signal::Toggle,
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub struct Signals;
+
+#[cfg(not(unix))]
+impl Signals {
+    pub fn new() -> Self {
+        Self
+    }
+
+    /// Waits for the next signal.
+    /// Only Ctrl-C is supported on this platform.
+    pub async fn recv(&mut self) -> Signal {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for Ctrl-C");
+        Signal::Terminate
+    }
+}
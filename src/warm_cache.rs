@@ -0,0 +1,85 @@
+use std::collections::BTreeSet;
+
+use mpd_client::commands::List;
+use mpd_client::tag::Tag;
+use tracing::error;
+
+use crate::album_art::{AlbumArtClient, WarmOutcome};
+use crate::config::Config;
+use crate::mpd_conn;
+
+/// Resolves the album art for every album in the library
+/// of each reachable MPD host into the persistent cache.
+///
+/// Albums which are already cached are skipped,
+/// so an interrupted run can be resumed by starting it again.
+pub async fn run(config: &Config) {
+    let mut albums = BTreeSet::new();
+
+    for host in &config.hosts {
+        let Some(client) = mpd_conn::connect(host).await else {
+            continue;
+        };
+
+        match client
+            .command(List::new(Tag::Album).group_by([Tag::AlbumArtist, Tag::MusicBrainzReleaseId]))
+            .await
+        {
+            Ok(list) => albums.extend(
+                list.grouped_values()
+                    .filter(|(album, _)| !album.is_empty())
+                    .map(|(album, [album_artist, release_id])| {
+                        (
+                            album_artist.to_string(),
+                            album.to_string(),
+                            release_id.to_string(),
+                        )
+                    }),
+            ),
+            Err(err) => error!("Failed to list albums on MPD host '{}': {err:?}", host.name),
+        }
+    }
+
+    let total = albums.len();
+    println!("Found {total} albums");
+
+    let mut album_art_client = AlbumArtClient::new(&config.album_art);
+    let mut unmatched = vec![];
+
+    for (i, (artist, album, release_id)) in albums.iter().enumerate() {
+        let release_id = Some(release_id.as_str()).filter(|id| !id.is_empty());
+
+        let outcome = if artist.is_empty() {
+            WarmOutcome::Unmatched
+        } else {
+            album_art_client.warm(artist, album, release_id).await
+        };
+
+        let label = match outcome {
+            WarmOutcome::Cached => "cached",
+            WarmOutcome::Resolved => "resolved",
+            WarmOutcome::Unmatched => {
+                unmatched.push((artist, album));
+                "not found"
+            }
+        };
+
+        println!("[{}/{total}] {artist} - {album}: {label}", i + 1);
+    }
+
+    println!(
+        "Done. {} of {total} albums have art cached.",
+        total - unmatched.len()
+    );
+
+    if !unmatched.is_empty() {
+        println!("Could not match the following albums:");
+        for (artist, album) in unmatched {
+            if artist.is_empty() {
+                println!("  {album} (no album artist tag)");
+            } else {
+                println!("  {artist} - {album}");
+            }
+        }
+    }
+}